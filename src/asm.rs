@@ -0,0 +1,245 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::ops::Range;
+
+use crate::interpreter::{CompilerCall, Instruction};
+use crate::module::{Constant, Module};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AsmError {
+    UnknownInstruction(String),
+    OperandCount { ins: Instruction, expected: usize, found: usize },
+    InvalidOperand(String),
+    OperandRange { operand: String, width: usize },
+    UnknownLabel(String),
+    DuplicateLabel(String),
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AsmError::UnknownInstruction(name) => write!(f, "unknown instruction '{}'", name),
+            AsmError::OperandCount { ins, expected, found } => write!(f, "{:?} takes {} operands, found {}", ins, expected, found),
+            AsmError::InvalidOperand(operand) => write!(f, "invalid operand '{}'", operand),
+            AsmError::OperandRange { operand, width } => write!(f, "operand '{}' does not fit in {} bytes", operand, width),
+            AsmError::UnknownLabel(label) => write!(f, "unknown label '{}'", label),
+            AsmError::DuplicateLabel(label) => write!(f, "label '{}' is already defined", label),
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// Builds a module a line at a time from the syntax `Disassembly` prints, so its
+// output can be fed back in, offsets and all. On top of that a line may start
+// with a `label:`, operands may name a label defined on an earlier line, a string
+// literal to `PushConst` or a host function to `CompilerCall`, and `;` starts a
+// comment. Labels have to be defined before they are used, as each line is
+// assembled on its own.
+#[derive(Clone, Debug, Default)]
+pub struct Assembler {
+    module: Module,
+    labels: BTreeMap<String, usize>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler::default()
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    pub fn into_module(self) -> Module {
+        self.module
+    }
+
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).copied()
+    }
+
+    // Appends one line, returning the code it produced. A line that fails leaves
+    // the assembler as it was.
+    pub fn assemble_line(&mut self, line: &str) -> Result<Range<usize>, AsmError> {
+        let start = self.module.code.len();
+        let mut line = strip_comment(line).trim();
+
+        if let Some((offset, rest)) = line.split_once(':') {
+            if !offset.is_empty() && offset.trim().bytes().all(|c| c.is_ascii_digit()) {
+                line = rest.trim();
+            }
+        }
+        let mut label = None;
+        if let Some((name, rest)) = line.split_once(':') {
+            if is_identifier(name.trim()) && !rest.trim_start().starts_with('"') {
+                let name = name.trim();
+                if self.labels.contains_key(name) {
+                    return Err(AsmError::DuplicateLabel(name.to_string()));
+                }
+                label = Some(name.to_string());
+                line = rest.trim();
+            }
+        }
+
+        if !line.is_empty() {
+            let (name, operands) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let ins = instruction(name).ok_or_else(|| AsmError::UnknownInstruction(name.to_string()))?;
+            let operands: Vec<&str> = match operands.trim() {
+                "" => Vec::new(),
+                operands => split_operands(operands),
+            };
+            let widths = ins.operands();
+            if operands.len() != widths.len() {
+                return Err(AsmError::OperandCount { ins, expected: widths.len(), found: operands.len() });
+            }
+
+            let mut code = vec![u8::from(ins)];
+            let mut constants = self.module.constants.clone();
+            for (operand, &width) in operands.iter().zip(widths) {
+                let val = self.operand(ins, operand, &mut constants)?;
+                let fits = match width {
+                    1 => (-128..=255).contains(&val),
+                    _ => (i32::MIN as i64..=u32::MAX as i64).contains(&val),
+                };
+                if !fits {
+                    return Err(AsmError::OperandRange { operand: operand.to_string(), width });
+                }
+                code.extend_from_slice(&(val as u32).to_le_bytes()[..width]);
+            }
+            self.module.code.extend(code);
+            self.module.constants = constants;
+        }
+        if let Some(label) = label {
+            self.labels.insert(label, start);
+        }
+        Ok(start..self.module.code.len())
+    }
+
+    fn operand(&self, ins: Instruction, operand: &str, constants: &mut Vec<Constant>) -> Result<i64, AsmError> {
+        if let Some(val) = parse_int(operand) {
+            return Ok(val);
+        }
+        if ins == Instruction::PushConst {
+            if let Some(str) = parse_string(operand) {
+                let constant = Constant::String(str);
+                let index = constants.iter().position(|c| *c == constant).unwrap_or_else(|| {
+                    constants.push(constant);
+                    constants.len() - 1
+                });
+                return Ok(index as i64);
+            }
+        }
+        if ins == Instruction::CompilerCall {
+            match CompilerCall::from(operand) {
+                CompilerCall::None => {}
+                call => return Ok(u8::from(call) as i64),
+            }
+        }
+        if is_identifier(operand) {
+            return self.label(operand).map(|offset| offset as i64).ok_or_else(|| AsmError::UnknownLabel(operand.to_string()));
+        }
+        Err(AsmError::InvalidOperand(operand.to_string()))
+    }
+}
+
+// Mnemonics are the instruction names, in any case.
+fn instruction(name: &str) -> Option<Instruction> {
+    (0..=u8::MAX).map_while(Instruction::decode).find(|ins| format!("{:?}", ins).eq_ignore_ascii_case(name))
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_int(operand: &str) -> Option<i64> {
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, operand),
+    };
+    let val = match digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None if digits.bytes().all(|c| c.is_ascii_digit()) => digits.parse().ok()?,
+        None => return None,
+    };
+    Some(if negative { -val } else { val })
+}
+
+fn parse_string(operand: &str) -> Option<String> {
+    let inner = operand.strip_prefix('"')?.strip_suffix('"')?;
+    let mut str = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        str.push(match c {
+            '\\' => match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c @ ('\\' | '"') => c,
+                _ => return None,
+            },
+            '"' => return None,
+            c => c,
+        });
+    }
+    Some(str)
+}
+
+// Commas and semicolons inside string literals do not count.
+fn in_string(line: &str, index: usize) -> bool {
+    let mut inside = false;
+    let mut escaped = false;
+    for c in line[..index].chars() {
+        match c {
+            '\\' if inside && !escaped => {
+                escaped = true;
+                continue;
+            }
+            '"' if !escaped => inside = !inside,
+            _ => {}
+        }
+        escaped = false;
+    }
+    inside
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.match_indices(';').find(|(index, _)| !in_string(line, *index)) {
+        Some((index, _)) => &line[..index],
+        None => line,
+    }
+}
+
+fn split_operands(operands: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    for (index, _) in operands.match_indices(',').filter(|(index, _)| !in_string(operands, *index)) {
+        parts.push(operands[start..index].trim());
+        start = index + 1;
+    }
+    parts.push(operands[start..].trim());
+    parts
+}
@@ -0,0 +1,73 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use bytecode::debugger::Debugger;
+use bytecode::gdb::{self, GdbStub};
+use bytecode::interpreter::Interpreter;
+use bytecode::module::Module;
+use bytecode::program::Program;
+
+const USAGE: &str = "usage : bytecode-gdb <program> [--tcp <address> | --unix <path>]";
+
+// Waits for one gdb connection and debugs `program` over it, e.g. with
+// `target remote localhost:1234` from gdb.
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (path, listen) = match args.as_slice() {
+        [path] => (path, None),
+        [path, kind, address] => (path, Some((kind.as_str(), address.as_str()))),
+        _ => fail(USAGE),
+    };
+
+    let bytes = std::fs::read(path).unwrap_or_else(|err| fail(&format!("{} : {}", path, err)));
+    let module = Module::from_bytes(&bytes).unwrap_or_else(|err| fail(&format!("{} : {}", path, err)));
+    let program = Program::new(module).unwrap_or_else(|err| fail(&format!("{} : {}", path, err)));
+    let mut stub = GdbStub::new(Debugger::new(Interpreter::with_program(Arc::new(program))));
+
+    let result = match listen.unwrap_or(("--tcp", "127.0.0.1:1234")) {
+        ("--tcp", address) => TcpListener::bind(address).and_then(|listener| {
+            eprintln!("bytecode-gdb : listening on {}", listener.local_addr()?);
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            gdb::serve(&mut stub, stream.try_clone()?, stream)
+        }),
+        #[cfg(unix)]
+        ("--unix", path) => std::os::unix::net::UnixListener::bind(path).and_then(|listener| {
+            eprintln!("bytecode-gdb : listening on {}", path);
+            let (stream, _) = listener.accept()?;
+            let result = gdb::serve(&mut stub, stream.try_clone()?, stream);
+            let _ = std::fs::remove_file(path);
+            result
+        }),
+        _ => fail(USAGE),
+    };
+    if let Err(err) = result {
+        fail(&format!("{}", err));
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("bytecode-gdb : {}", message);
+    std::process::exit(1)
+}
@@ -0,0 +1,172 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::sync::Arc;
+
+use bytecode::asm::Assembler;
+use bytecode::interpreter::Interpreter;
+use bytecode::program::Program;
+
+// Instructions one line may run before it is taken back, so a stray jump can not
+// hang the session.
+const STEP_LIMIT: u64 = 1_000_000;
+
+const HELP: &str = "\
+Lines are assembled and run right away, e.g. `Push 2`, `top: Push -1` or
+`PushConst \"hi\"`. A line that traps is taken back.
+  :stack    the stack, top first
+  :frames   the call frames
+  :flags    the flags that are set
+  :disasm   everything entered so far
+  :reset    start over with an empty program
+  :help     this text
+  :quit     leave, as does the end of input";
+
+// Every line is appended to one program, which is verified again and swapped in
+// under the interpreter before the new code runs.
+struct Repl {
+    assembler: Assembler,
+    interpreter: Interpreter,
+}
+
+impl Repl {
+    fn new() -> Repl {
+        let assembler = Assembler::new();
+        let interpreter = Interpreter::from_module(assembler.module().clone());
+        Repl { assembler, interpreter }
+    }
+
+    // Returns false once the session should end.
+    fn line(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        match line.trim() {
+            ":quit" | ":q" => return Ok(false),
+            ":help" => writeln!(out, "{}", HELP)?,
+            ":stack" => self.stack(out)?,
+            ":frames" => write!(out, "{}", self.interpreter.backtrace())?,
+            ":flags" => {
+                let set: Vec<_> = self.interpreter.flags().fields().into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect();
+                writeln!(out, "{}", if set.is_empty() { "none".to_string() } else { set.join(" ") })?;
+            }
+            ":disasm" => write!(out, "{}", self.interpreter.disassembly())?,
+            ":reset" => *self = Repl::new(),
+            command if command.starts_with(':') => writeln!(out, "unknown command {}, try :help", command)?,
+            line => self.execute(line, out)?,
+        }
+        Ok(true)
+    }
+
+    fn execute(&mut self, line: &str, out: &mut impl Write) -> io::Result<()> {
+        let saved = self.assembler.clone();
+        let code = match self.assembler.assemble_line(line) {
+            Ok(code) => code,
+            Err(err) => return writeln!(out, "error : {}", err),
+        };
+        if code.is_empty() {
+            return Ok(());
+        }
+        let program = match Program::new(self.assembler.module().clone()) {
+            Ok(program) => Arc::new(program),
+            Err(err) => {
+                self.assembler = saved;
+                return writeln!(out, "error : {}", err);
+            }
+        };
+
+        let before = self.interpreter.snapshot();
+        let old_program = self.interpreter.program().clone();
+        if self.interpreter.set_program(program).is_err() {
+            self.assembler = saved;
+            return writeln!(out, "error : the line changed code that already ran");
+        }
+        match self.run() {
+            Ok(()) => match self.interpreter.stack_depth() {
+                0 => writeln!(out, "depth 0"),
+                depth => writeln!(out, "depth {}, top {}", depth, self.interpreter.peek_value()),
+            },
+            Err(message) => {
+                self.assembler = saved;
+                match Interpreter::restore(old_program, &before) {
+                    Ok(interpreter) => {
+                        self.interpreter = interpreter;
+                        writeln!(out, "{}\nline taken back", message)
+                    }
+                    Err(err) => {
+                        *self = Repl::new();
+                        writeln!(out, "{}\ncould not take the line back : {}\nstarting over", message, err)
+                    }
+                }
+            }
+        }
+    }
+
+    // Runs until execution reaches the end of the code entered so far.
+    fn run(&mut self) -> Result<(), String> {
+        let end = self.interpreter.program().len();
+        let mut steps = 0;
+        while self.interpreter.pc() < end {
+            if steps == STEP_LIMIT {
+                return Err(format!("still running after {} instructions", STEP_LIMIT));
+            }
+            steps += 1;
+            self.interpreter.single_step().map_err(|trap| trap.to_string().trim_end().to_string())?;
+        }
+        Ok(())
+    }
+
+    fn stack(&self, out: &mut impl Write) -> io::Result<()> {
+        let registers = self.interpreter.registers();
+        if self.interpreter.stack_depth() == 0 {
+            return writeln!(out, "(empty)");
+        }
+        for slot in (1..=self.interpreter.stack_depth()).map(|depth| registers.stack_ptr + depth) {
+            let val = self.interpreter.stack_value(slot).map_or_else(|err| err.to_string(), |val| val.to_string());
+            let marker = if slot == registers.frame_ptr { "  <- frame_ptr" } else { "" };
+            writeln!(out, "{:>6} : {}{}", slot, val, marker)?;
+        }
+        Ok(())
+    }
+}
+
+fn main() -> io::Result<()> {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut out = io::stdout().lock();
+    let mut repl = Repl::new();
+    if interactive {
+        writeln!(out, "bytecode repl, :help lists the commands")?;
+    }
+
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+        let Some(line) = lines.next().transpose()? else {
+            break;
+        };
+        if !repl.line(&line, &mut out)? {
+            break;
+        }
+    }
+    Ok(())
+}
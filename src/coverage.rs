@@ -0,0 +1,193 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+use crate::hook::ExecutionHook;
+use crate::interpreter::{operand_values, Instruction, Interpreter};
+use crate::program::Program;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineCoverage {
+    pub file: String,
+    pub line: u32,
+    pub hits: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CoverageSummary {
+    pub instructions: (usize, usize),
+    pub branches: (usize, usize),
+    pub lines: (usize, usize),
+}
+
+impl Display for CoverageSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = [("instructions", self.instructions), ("branches", self.branches), ("lines", self.lines)];
+        for (name, (covered, total)) in rows {
+            let percent = if total == 0 { 100.0 } else { covered as f64 * 100.0 / total as f64 };
+            writeln!(f, "{:<12} {:>6} / {:<6} {:>6.1}%", name, covered, total, percent)?;
+        }
+        Ok(())
+    }
+}
+
+// Records how often each instruction offset ran and which way every `Jz` and
+// `Jnz` went. Collect one per run and `merge` them to cover a whole test suite.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    hits: BTreeMap<usize, u64>,
+    branches: BTreeMap<usize, BranchCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub fn hits(&self, offset: usize) -> u64 {
+        self.hits.get(&offset).copied().unwrap_or(0)
+    }
+
+    pub fn branch(&self, offset: usize) -> BranchCoverage {
+        self.branches.get(&offset).copied().unwrap_or_default()
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (offset, hits) in &other.hits {
+            *self.hits.entry(*offset).or_insert(0) += hits;
+        }
+        for (offset, branch) in &other.branches {
+            let entry = self.branches.entry(*offset).or_default();
+            entry.taken += branch.taken;
+            entry.not_taken += branch.not_taken;
+        }
+    }
+
+    // A line counts as often as its most executed instruction.
+    pub fn lines(&self, program: &Program) -> Vec<LineCoverage> {
+        let mut lines: BTreeMap<(String, u32), u64> = BTreeMap::new();
+        for offset in instruction_offsets(program.code()) {
+            if let Some(location) = program.debug_info().location(offset) {
+                let hits = lines.entry((location.file, location.line)).or_insert(0);
+                *hits = (*hits).max(self.hits(offset));
+            }
+        }
+        lines.into_iter().map(|((file, line), hits)| LineCoverage { file, line, hits }).collect()
+    }
+
+    // Every conditional jump has two edges, each covered once it went that way.
+    pub fn summary(&self, program: &Program) -> CoverageSummary {
+        let mut summary = CoverageSummary::default();
+        for offset in instruction_offsets(program.code()) {
+            summary.instructions.1 += 1;
+            summary.instructions.0 += (self.hits(offset) != 0) as usize;
+            if is_conditional(Instruction::from(program.code()[offset])) {
+                let branch = self.branch(offset);
+                summary.branches.1 += 2;
+                summary.branches.0 += (branch.taken != 0) as usize + (branch.not_taken != 0) as usize;
+            }
+        }
+        let lines = self.lines(program);
+        summary.lines = (lines.iter().filter(|line| line.hits != 0).count(), lines.len());
+        summary
+    }
+
+    pub fn annotated<'a>(&'a self, program: &'a Program) -> AnnotatedDisassembly<'a> {
+        AnnotatedDisassembly { coverage: self, program }
+    }
+}
+
+impl ExecutionHook for Coverage {
+    fn before_instruction(&mut self, _vm: &Interpreter, pc: usize, _ins: Instruction) {
+        *self.hits.entry(pc).or_insert(0) += 1;
+    }
+
+    fn after_instruction(&mut self, vm: &Interpreter, pc: usize, ins: Instruction) {
+        if is_conditional(ins) {
+            let branch = self.branches.entry(pc).or_default();
+            if vm.pc() == pc + 1 + ins.operand_len() {
+                branch.not_taken += 1;
+            } else {
+                branch.taken += 1;
+            }
+        }
+    }
+}
+
+fn is_conditional(ins: Instruction) -> bool {
+    matches!(ins, Instruction::Jz | Instruction::Jnz)
+}
+
+fn instruction_offsets(code: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let ins = Instruction::from(*code.get(offset)?);
+        let current = offset;
+        offset += 1 + ins.operand_len();
+        Some(current)
+    })
+}
+
+// The disassembly with each instruction prefixed by its hit count, `#####` for
+// code that never ran, and the edges taken by every conditional jump.
+pub struct AnnotatedDisassembly<'a> {
+    coverage: &'a Coverage,
+    program: &'a Program,
+}
+
+impl Display for AnnotatedDisassembly<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = self.program.code();
+        let mut last_location = None;
+        for offset in instruction_offsets(code) {
+            if let Some(location) = self.program.debug_info().location(offset) {
+                if last_location.as_ref() != Some(&location) {
+                    writeln!(f, "{:>8} | ; {}", "", location)?;
+                    last_location = Some(location);
+                }
+            }
+
+            let ins = Instruction::from(code[offset]);
+            match self.coverage.hits(offset) {
+                0 => write!(f, "{:>8} | ", "#####")?,
+                hits => write!(f, "{:>8} | ", hits)?,
+            }
+            write!(f, "{} : {:?}", offset, ins)?;
+            for (i, val) in operand_values(code, offset).iter().enumerate() {
+                write!(f, "{}{}", if i == 0 { " " } else { ", " }, val)?;
+            }
+            if is_conditional(ins) {
+                let branch = self.coverage.branch(offset);
+                write!(f, " ; taken {}, not taken {}", branch.taken, branch.not_taken)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
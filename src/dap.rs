@@ -0,0 +1,689 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use crate::debugger::{Debugger, StopReason};
+use crate::gc::Value;
+use crate::interpreter::{Flags, Interpreter};
+use crate::json::Json;
+use crate::module::Module;
+use crate::program::Program;
+
+// Instructions run between looks at the request queue while the program runs,
+// which is what lets `pause` interrupt an endless loop.
+const SLICE: usize = 4096;
+const MAX_MESSAGE: usize = 16 << 20;
+const THREAD_ID: i64 = 1;
+// Deeper nesting in an `evaluate` expression is rejected rather than risking the stack.
+const MAX_DEPTH: usize = 64;
+
+// Locals of frame `n` are variables reference `LOCALS_REFERENCE + n`.
+const STACK_REFERENCE: usize = 1;
+const REGISTERS_REFERENCE: usize = 2;
+const LOCALS_REFERENCE: usize = 3;
+
+// Reads one message in the base protocol framing, a `Content-Length` header, a
+// blank line and the JSON body. Returns None once the input is closed.
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Json>> {
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return match length {
+                None => Ok(None),
+                Some(_) => Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, val)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = val.trim().parse::<usize>().ok().filter(|len| *len <= MAX_MESSAGE);
+                if length.is_none() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length"));
+                }
+            }
+        }
+    }
+
+    let mut body = vec![0; length.unwrap_or_default()];
+    input.read_exact(&mut body)?;
+    let text = String::from_utf8(body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Json::parse(&text).map(Some).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// Runs a debug session until the client disconnects or closes the input. Requests
+// are read on their own thread so they still arrive while the program is running.
+pub fn serve(input: impl BufRead + Send + 'static, mut output: impl Write) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = input;
+        loop {
+            let message = read_message(&mut input).transpose();
+            let last = !matches!(message, Some(Ok(_)));
+            if message.is_some_and(|message| sender.send(message).is_err()) || last {
+                break;
+            }
+        }
+    });
+
+    let mut session = Session::new();
+    while !session.is_finished() {
+        let messages = if session.is_running() {
+            match receiver.try_recv() {
+                Ok(request) => session.handle(&request?),
+                Err(TryRecvError::Empty) => session.poll(),
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(request) => session.handle(&request?),
+                Err(_) => break,
+            }
+        };
+        for message in &messages {
+            write_message(&mut output, message)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StepKind {
+    Continue,
+    In,
+    Over,
+    Out,
+}
+
+// Where a resumed run has to stop, besides breakpoints. `line` is None when
+// stepping by instruction, which is also what happens without debug info.
+struct Resume {
+    kind: StepKind,
+    depth: usize,
+    line: Option<(String, u32)>,
+}
+
+impl Resume {
+    fn done(&self, vm: &Interpreter) -> bool {
+        let depth = vm.call_stack().frames.len();
+        let moved = self.line.is_none() || line_at(vm, vm.pc()) != self.line;
+        match self.kind {
+            StepKind::Continue => false,
+            StepKind::In => depth != self.depth || moved,
+            StepKind::Over => depth < self.depth || (depth == self.depth && moved),
+            StepKind::Out => depth < self.depth,
+        }
+    }
+}
+
+// One debug adapter session over a single interpreter. `handle` answers a request
+// and `poll` runs a resumed program a slice further, both return the messages to
+// send back.
+pub struct Session {
+    seq: i64,
+    debugger: Option<Debugger>,
+    source_breakpoints: BTreeMap<String, Vec<u32>>,
+    instruction_breakpoints: Vec<usize>,
+    stop_on_entry: bool,
+    resume: Option<Resume>,
+    trapped: bool,
+    finished: bool,
+    events: Vec<(&'static str, Json)>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            seq: 0,
+            debugger: None,
+            source_breakpoints: BTreeMap::new(),
+            instruction_breakpoints: Vec::new(),
+            stop_on_entry: false,
+            resume: None,
+            trapped: false,
+            finished: false,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.resume.is_some()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.debugger.as_ref()
+    }
+
+    pub fn handle(&mut self, request: &Json) -> Vec<Json> {
+        let command = request.get("command").and_then(Json::as_str).unwrap_or_default();
+        let args = request.get("arguments").unwrap_or(&Json::Null);
+        let result = self.dispatch(command, args);
+
+        let mut response = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(result.is_ok())),
+            ("command", Json::from(command)),
+        ];
+        match result {
+            Ok(Json::Null) => {}
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", Json::from(message))),
+        }
+        let mut messages = vec![self.message(response)];
+        messages.extend(self.flush());
+        messages
+    }
+
+    pub fn poll(&mut self) -> Vec<Json> {
+        if let Some(resume) = self.resume.take() {
+            self.run(resume);
+        }
+        self.flush()
+    }
+
+    fn dispatch(&mut self, command: &str, args: &Json) -> Result<Json, String> {
+        match command {
+            "initialize" => Ok(Json::object([
+                ("supportsConfigurationDoneRequest", Json::from(true)),
+                ("supportsStepBack", Json::from(true)),
+                ("supportsInstructionBreakpoints", Json::from(true)),
+                ("supportsSteppingGranularity", Json::from(true)),
+                ("supportsTerminateRequest", Json::from(true)),
+            ])),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    self.stopped("entry", None);
+                    Ok(Json::Null)
+                } else {
+                    self.resume(StepKind::Continue, args)
+                }
+            }
+            "threads" => Ok(Json::object([(
+                "threads",
+                Json::from(vec![Json::object([("id", Json::from(THREAD_ID)), ("name", Json::from("main"))])]),
+            )])),
+            "continue" => self.resume(StepKind::Continue, args),
+            "stepIn" => self.resume(StepKind::In, args),
+            "next" => self.resume(StepKind::Over, args),
+            "stepOut" => self.resume(StepKind::Out, args),
+            "stepBack" => self.step_back(args),
+            "reverseContinue" => self.reverse_continue(),
+            "pause" => {
+                if self.resume.take().is_some() {
+                    self.stopped("pause", None);
+                }
+                Ok(Json::Null)
+            }
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => self.scopes(args),
+            "variables" => self.variables(args),
+            "evaluate" => self.evaluate(args),
+            "disconnect" | "terminate" => {
+                self.resume = None;
+                self.finished = true;
+                Ok(Json::Null)
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        }
+    }
+
+    fn launch(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("program").and_then(Json::as_str).ok_or("launch needs a 'program' path")?;
+        let bytes = std::fs::read(path).map_err(|err| format!("{} : {}", path, err))?;
+        let module = Module::from_bytes(&bytes).map_err(|err| format!("{} : {}", path, err))?;
+        let program = Program::new(module).map_err(|err| format!("{} : {}", path, err))?;
+
+        self.debugger = Some(Debugger::new(Interpreter::with_program(Arc::new(program))));
+        self.stop_on_entry = args.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+        self.trapped = false;
+        self.sync_breakpoints();
+        self.events.push(("initialized", Json::Null));
+        Ok(Json::Null)
+    }
+
+    // Lines resolve to offsets only once a program is loaded, until then they
+    // are kept and reported unverified.
+    fn set_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let path = args.get("source").and_then(|source| source.get("path")).and_then(Json::as_str).ok_or("breakpoints need a source path")?;
+        let lines: Vec<u32> = args.get("breakpoints").and_then(Json::as_array).unwrap_or_default().iter()
+            .filter_map(|breakpoint| breakpoint.get("line")?.as_i64())
+            .map(|line| line as u32)
+            .collect();
+
+        let breakpoints = lines.iter().map(|&line| match self.resolve_line(path, line) {
+            Some((offset, line)) => Json::object([
+                ("verified", Json::from(true)),
+                ("line", Json::from(line)),
+                ("instructionReference", Json::from(offset.to_string())),
+            ]),
+            None => Json::object([("verified", Json::from(false)), ("line", Json::from(line))]),
+        }).collect();
+        self.source_breakpoints.insert(path.to_string(), lines);
+        self.sync_breakpoints();
+        Ok(Json::object([("breakpoints", Json::Array(breakpoints))]))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Json) -> Result<Json, String> {
+        let code_len = self.debugger.as_ref().map(|debugger| debugger.interpreter().program().len());
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for breakpoint in args.get("breakpoints").and_then(Json::as_array).unwrap_or_default() {
+            let reference = breakpoint.get("instructionReference").and_then(Json::as_str).and_then(|reference| reference.parse::<i64>().ok());
+            let offset = breakpoint.get("offset").and_then(Json::as_i64).unwrap_or(0);
+            let target = reference.and_then(|reference| usize::try_from(reference + offset).ok());
+            let verified = matches!((target, code_len), (Some(target), Some(len)) if target < len);
+            if let Some(target) = target {
+                self.instruction_breakpoints.push(target);
+            }
+            breakpoints.push(Json::object([("verified", Json::from(verified))]));
+        }
+        self.sync_breakpoints();
+        Ok(Json::object([("breakpoints", Json::Array(breakpoints))]))
+    }
+
+    fn resolve_line(&self, path: &str, line: u32) -> Option<(usize, u32)> {
+        self.debugger.as_ref()?.interpreter().program().debug_info().find_line(path, line)
+    }
+
+    fn sync_breakpoints(&mut self) {
+        let mut offsets = self.instruction_breakpoints.clone();
+        for (path, lines) in &self.source_breakpoints {
+            offsets.extend(lines.iter().filter_map(|&line| self.resolve_line(path, line)).map(|(offset, _)| offset));
+        }
+        let Some(debugger) = &mut self.debugger else {
+            return;
+        };
+        for offset in debugger.breakpoints().collect::<Vec<_>>() {
+            debugger.remove_breakpoint(offset);
+        }
+        for offset in offsets {
+            debugger.add_breakpoint(offset);
+        }
+    }
+
+    fn resume(&mut self, kind: StepKind, args: &Json) -> Result<Json, String> {
+        let vm = self.vm()?;
+        let by_instruction = args.get("granularity").and_then(Json::as_str) == Some("instruction");
+        let line = if by_instruction { None } else { line_at(vm, vm.pc()) };
+        self.resume = Some(Resume { kind, depth: vm.call_stack().frames.len(), line });
+        Ok(match kind {
+            StepKind::Continue => Json::object([("allThreadsContinued", Json::from(true))]),
+            _ => Json::Null,
+        })
+    }
+
+    fn run(&mut self, resume: Resume) {
+        let Some(debugger) = &mut self.debugger else {
+            return;
+        };
+        for _ in 0..SLICE {
+            match debugger.step() {
+                StopReason::Halted => return self.exited(0),
+                StopReason::Trapped(_) if self.trapped => return self.exited(1),
+                StopReason::Trapped(trap) => {
+                    self.trapped = true;
+                    return self.stopped("exception", Some(trap.to_string()));
+                }
+                _ => {}
+            }
+            let pc = debugger.pc();
+            if debugger.breakpoints().any(|offset| offset == pc) {
+                return self.stopped("breakpoint", None);
+            }
+            if resume.done(debugger.interpreter()) {
+                return self.stopped("step", None);
+            }
+        }
+        self.resume = Some(resume);
+    }
+
+    fn step_back(&mut self, args: &Json) -> Result<Json, String> {
+        let by_instruction = args.get("granularity").and_then(Json::as_str) == Some("instruction");
+        let debugger = self.debugger.as_mut().ok_or("no program is running")?;
+        let vm = debugger.interpreter();
+        let (depth, line) = (vm.call_stack().frames.len(), line_at(vm, vm.pc()));
+        let mut reason = "step";
+        while debugger.step_back() != StopReason::StartOfHistory {
+            let vm = debugger.interpreter();
+            if debugger.breakpoints().any(|offset| offset == vm.pc()) {
+                reason = "breakpoint";
+                break;
+            }
+            if by_instruction || line.is_none() || vm.call_stack().frames.len() != depth || line_at(vm, vm.pc()) != line {
+                break;
+            }
+        }
+        self.resume = None;
+        self.trapped = false;
+        self.stopped(reason, None);
+        Ok(Json::Null)
+    }
+
+    fn reverse_continue(&mut self) -> Result<Json, String> {
+        let debugger = self.debugger.as_mut().ok_or("no program is running")?;
+        let reason = match debugger.reverse_continue() {
+            StopReason::Breakpoint(_) => "breakpoint",
+            _ => "step",
+        };
+        self.resume = None;
+        self.trapped = false;
+        self.stopped(reason, None);
+        Ok(Json::Null)
+    }
+
+    fn stack_trace(&mut self, args: &Json) -> Result<Json, String> {
+        let vm = self.vm()?;
+        let frames = vm.call_stack().frames;
+        let start = args.get("startFrame").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+        let levels = args.get("levels").and_then(Json::as_i64).filter(|levels| *levels > 0).map_or(frames.len(), |levels| levels as usize);
+
+        let stack_frames = frames.iter().enumerate().skip(start).take(levels).map(|(id, frame)| {
+            let name = frame.function.clone().unwrap_or_else(|| format!("fn@{}", frame.pc));
+            let mut fields = vec![
+                ("id", Json::from(id)),
+                ("name", Json::from(name)),
+                ("instructionPointerReference", Json::from(frame.pc.to_string())),
+            ];
+            match frame.location.clone() {
+                Some(location) => {
+                    let name = std::path::Path::new(&location.file).file_name().map(|name| name.to_string_lossy().into_owned());
+                    fields.push(("source", Json::object([
+                        ("name", Json::from(name.unwrap_or_else(|| location.file.clone()))),
+                        ("path", Json::from(location.file)),
+                    ])));
+                    fields.push(("line", Json::from(location.line)));
+                    fields.push(("column", Json::from(location.column)));
+                }
+                None => {
+                    fields.push(("line", Json::from(0)));
+                    fields.push(("column", Json::from(0)));
+                }
+            }
+            Json::object(fields)
+        }).collect();
+        Ok(Json::object([("stackFrames", Json::Array(stack_frames)), ("totalFrames", Json::from(frames.len()))]))
+    }
+
+    fn scopes(&mut self, args: &Json) -> Result<Json, String> {
+        let frame = args.get("frameId").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+        let scope = |name: &str, reference: usize| Json::object([
+            ("name", Json::from(name)),
+            ("variablesReference", Json::from(reference)),
+            ("expensive", Json::from(false)),
+        ]);
+        Ok(Json::object([(
+            "scopes",
+            Json::from(vec![
+                scope("Locals", LOCALS_REFERENCE + frame),
+                scope("Registers", REGISTERS_REFERENCE),
+                scope("Stack", STACK_REFERENCE),
+            ]),
+        )]))
+    }
+
+    fn variables(&mut self, args: &Json) -> Result<Json, String> {
+        let vm = self.vm()?;
+        let reference = args.get("variablesReference").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+        let variable = |name: String, value: String| Json::object([
+            ("name", Json::from(name.clone())),
+            ("value", Json::from(value)),
+            ("evaluateName", Json::from(name)),
+            ("variablesReference", Json::from(0)),
+        ]);
+        let slot = |slot: usize| vm.stack_value(slot).map_or_else(|err| err.to_string(), |val| val.to_string());
+
+        let registers = vm.registers();
+        let variables = match reference {
+            STACK_REFERENCE => (1..=vm.stack_depth())
+                .map(|depth| registers.stack_ptr + depth)
+                .map(|index| variable(format!("[{}]", index), slot(index)))
+                .collect(),
+            REGISTERS_REFERENCE => vec![
+                variable("ptr".to_string(), registers.ptr.to_string()),
+                variable("ins_ptr".to_string(), registers.ins_ptr.to_string()),
+                variable("stack_ptr".to_string(), registers.stack_ptr.to_string()),
+                variable("frame_ptr".to_string(), registers.frame_ptr.to_string()),
+                variable("flags".to_string(), flag_names(&registers.flags)),
+            ],
+            // A frame's locals run from its frame pointer down to where the frame
+            // it called, or the top of the stack, begins.
+            _ => {
+                let frames = vm.call_stack().frames;
+                let index = reference.checked_sub(LOCALS_REFERENCE).filter(|index| *index < frames.len()).ok_or("unknown variables reference")?;
+                let frame_ptr = frames[index].frame_ptr;
+                let bottom = match index {
+                    0 => registers.stack_ptr + 1,
+                    _ => frames[index - 1].frame_ptr + 3,
+                };
+                match frame_ptr {
+                    0 => Vec::new(),
+                    _ => (bottom..=frame_ptr).rev()
+                        .map(|index| variable(format!("[fp{:+}]", index as i64 - frame_ptr as i64), slot(index)))
+                        .collect(),
+                }
+            }
+        };
+        Ok(Json::object([("variables", Json::Array(variables))]))
+    }
+
+    fn evaluate(&mut self, args: &Json) -> Result<Json, String> {
+        let vm = self.vm()?;
+        let expression = args.get("expression").and_then(Json::as_str).unwrap_or_default();
+        let frame = args.get("frameId").and_then(Json::as_i64).unwrap_or(0).max(0) as usize;
+        let frame_ptr = vm.call_stack().frames.get(frame).ok_or("unknown frame")?.frame_ptr;
+        let result = evaluate(vm, frame_ptr, expression)?;
+        Ok(Json::object([("result", Json::from(result)), ("variablesReference", Json::from(0))]))
+    }
+
+    fn vm(&self) -> Result<&Interpreter, String> {
+        match &self.debugger {
+            Some(debugger) => Ok(debugger.interpreter()),
+            None => Err("no program is running".to_string()),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) {
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+        if let Some(text) = text {
+            body.push(("text", Json::from(text)));
+        }
+        self.events.push(("stopped", Json::object(body)));
+    }
+
+    fn exited(&mut self, code: i64) {
+        self.events.push(("exited", Json::object([("exitCode", Json::from(code))])));
+        self.events.push(("terminated", Json::Null));
+    }
+
+    fn message(&mut self, fields: Vec<(&str, Json)>) -> Json {
+        self.seq += 1;
+        let mut message = vec![("seq", Json::from(self.seq))];
+        message.extend(fields);
+        Json::object(message)
+    }
+
+    fn flush(&mut self) -> Vec<Json> {
+        std::mem::take(&mut self.events).into_iter().map(|(event, body)| {
+            let mut fields = vec![("type", Json::from("event")), ("event", Json::from(event))];
+            if body != Json::Null {
+                fields.push(("body", body));
+            }
+            self.message(fields)
+        }).collect()
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn line_at(vm: &Interpreter, pc: usize) -> Option<(String, u32)> {
+    vm.program().debug_info().location(pc).map(|location| (location.file, location.line))
+}
+
+fn flag_names(flags: &Flags) -> String {
+    let set: Vec<_> = flags.fields().into_iter().filter(|(_, set)| *set).map(|(name, _)| name).collect();
+    if set.is_empty() {
+        "none".to_string()
+    } else {
+        set.join(" ")
+    }
+}
+
+// Evaluates register expressions such as `frame_ptr`, `flags.equals` or `[fp-1]`,
+// where brackets read a stack slot. Terms combine with `+` and `-`, `fp` is the
+// frame pointer of the selected frame.
+pub fn evaluate(vm: &Interpreter, frame_ptr: usize, expression: &str) -> Result<String, String> {
+    if expression.trim() == "flags" {
+        return Ok(flag_names(vm.flags()));
+    }
+    let mut parser = Expression { vm, frame_ptr, text: expression.as_bytes(), pos: 0 };
+    let val = parser.sum(0)?;
+    parser.whitespace();
+    match parser.text.get(parser.pos) {
+        None => Ok(val.to_string()),
+        Some(c) => Err(format!("unexpected '{}' at {}", *c as char, parser.pos)),
+    }
+}
+
+struct Expression<'a> {
+    vm: &'a Interpreter,
+    frame_ptr: usize,
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Expression<'_> {
+    fn whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn sum(&mut self, depth: usize) -> Result<Value, String> {
+        let mut val = self.term(depth)?;
+        loop {
+            self.whitespace();
+            let sign = match self.text.get(self.pos) {
+                Some(b'+') => 1,
+                Some(b'-') => -1,
+                _ => return Ok(val),
+            };
+            self.pos += 1;
+            let rhs = self.term(depth)?.raw();
+            val = Value::Int(val.raw().wrapping_add(rhs.wrapping_mul(sign)));
+        }
+    }
+
+    fn term(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("expression nested too deeply".to_string());
+        }
+        self.whitespace();
+        let start = self.pos;
+        match self.text.get(self.pos) {
+            Some(b'[') => {
+                self.pos += 1;
+                let slot = self.sum(depth + 1)?.raw() as u32 as usize;
+                self.whitespace();
+                if self.text.get(self.pos) != Some(&b']') {
+                    return Err(format!("expected ']' at {}", self.pos));
+                }
+                self.pos += 1;
+                self.vm.stack_value(slot).map_err(|err| err.to_string())
+            }
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(Value::Int(self.term(depth + 1)?.raw().wrapping_neg()))
+            }
+            Some(b'0'..=b'9') => {
+                while self.text.get(self.pos).is_some_and(u8::is_ascii_alphanumeric) {
+                    self.pos += 1;
+                }
+                let text = String::from_utf8_lossy(&self.text[start..self.pos]);
+                let val = match text.strip_prefix("0x") {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => text.parse::<i64>(),
+                };
+                val.map(|val| Value::Int(val as i32)).map_err(|_| format!("invalid number '{}'", text))
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == b'_' => {
+                while self.text.get(self.pos).is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_' || *c == b'.') {
+                    self.pos += 1;
+                }
+                self.register(&String::from_utf8_lossy(&self.text[start..self.pos]))
+            }
+            Some(c) => Err(format!("unexpected '{}' at {}", *c as char, self.pos)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn register(&self, name: &str) -> Result<Value, String> {
+        let registers = self.vm.registers();
+        let val = match name {
+            "ptr" | "pc" => registers.ptr,
+            "ins_ptr" => registers.ins_ptr,
+            "stack.ptr" | "stack_ptr" | "sp" => registers.stack_ptr,
+            "frame_ptr" | "fp" => self.frame_ptr,
+            "depth" => self.vm.stack_depth(),
+            _ => {
+                let flag = name.strip_prefix("flags.").and_then(|flag| registers.flags.fields().into_iter().find(|(name, _)| *name == flag));
+                match flag {
+                    Some((_, set)) => set as usize,
+                    None => return Err(format!("unknown register '{}'", name)),
+                }
+            }
+        };
+        Ok(Value::Int(val as u32 as i32))
+    }
+}
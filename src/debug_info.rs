@@ -0,0 +1,192 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::fmt::Display;
+
+use crate::module::ModuleError;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LineEntry {
+    pub offset: u32,
+    pub file: u32,
+    pub line: u32,
+    pub column: u32,
+}
+
+// Maps bytecode offsets to source positions. Each entry covers the code from its
+// offset up to the offset of the next entry.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DebugInfo {
+    pub files: Vec<String>,
+    pub lines: Vec<LineEntry>,
+}
+
+impl DebugInfo {
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn add_location(&mut self, offset: u32, file: &str, line: u32, column: u32) {
+        let file = match self.files.iter().position(|f| f == file) {
+            Some(index) => index as u32,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() as u32 - 1
+            }
+        };
+        let entry = LineEntry { offset, file, line, column };
+
+        match self.lines.last_mut() {
+            Some(last) if last.offset == offset => *last = entry,
+            Some(last) if (last.file, last.line, last.column) == (file, line, column) => {}
+            _ => self.lines.push(entry),
+        }
+    }
+
+    pub fn location(&self, offset: usize) -> Option<SourceLocation> {
+        let index = self.lines.partition_point(|entry| entry.offset as usize <= offset);
+        let entry = self.lines.get(index.checked_sub(1)?)?;
+        Some(SourceLocation {
+            file: self.files.get(entry.file as usize)?.clone(),
+            line: entry.line,
+            column: entry.column,
+        })
+    }
+
+    // The first offset of `line` in `file`, or of the closest later line that has
+    // code. `file` matches an entry when it is the same path or ends with it.
+    pub fn find_line(&self, file: &str, line: u32) -> Option<(usize, u32)> {
+        let matches = |index: u32| {
+            self.files.get(index as usize).is_some_and(|name| name == file || std::path::Path::new(file).ends_with(name))
+        };
+        self.lines.iter()
+            .filter(|entry| entry.line >= line && matches(entry.file))
+            .min_by_key(|entry| (entry.line, entry.offset))
+            .map(|entry| (entry.offset as usize, entry.line))
+    }
+
+    // Entries are stored as deltas from the previous one in LEB128, which keeps the
+    // usual case of small forward steps down to a few bytes per entry. They are
+    // written in offset order, whatever order `lines` holds them in.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, self.files.len() as u64);
+        for file in &self.files {
+            write_varint(&mut bytes, file.len() as u64);
+            bytes.extend_from_slice(file.as_bytes());
+        }
+
+        let mut lines = self.lines.clone();
+        lines.sort_by_key(|entry| entry.offset);
+        write_varint(&mut bytes, lines.len() as u64);
+        let mut prev = LineEntry { offset: 0, file: 0, line: 0, column: 0 };
+        for entry in &lines {
+            write_varint(&mut bytes, (entry.offset - prev.offset) as u64);
+            write_varint(&mut bytes, entry.file as u64);
+            write_varint(&mut bytes, zigzag(entry.line as i64 - prev.line as i64));
+            write_varint(&mut bytes, entry.column as u64);
+            prev = *entry;
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<DebugInfo, ModuleError> {
+        let mut index = 0;
+        let mut debug_info = DebugInfo::default();
+
+        let files = read_varint(bytes, &mut index)?;
+        for _ in 0..files {
+            let len = read_varint(bytes, &mut index)? as usize;
+            let end = index.checked_add(len).ok_or(ModuleError::Truncated)?;
+            let file = bytes.get(index..end).ok_or(ModuleError::Truncated)?;
+            debug_info.files.push(String::from_utf8(file.to_vec()).map_err(|_| ModuleError::InvalidUtf8)?);
+            index = end;
+        }
+
+        let lines = read_varint(bytes, &mut index)?;
+        let mut prev = LineEntry { offset: 0, file: 0, line: 0, column: 0 };
+        for _ in 0..lines {
+            let offset = prev.offset.checked_add(read_u32(bytes, &mut index)?).ok_or(ModuleError::InvalidDebugInfo)?;
+            let file = read_u32(bytes, &mut index)?;
+            let line = (prev.line as i64).checked_add(unzigzag(read_varint(bytes, &mut index)?));
+            let line = line.and_then(|line| u32::try_from(line).ok()).ok_or(ModuleError::InvalidDebugInfo)?;
+            let entry = LineEntry { offset, file, line, column: read_u32(bytes, &mut index)? };
+            debug_info.lines.push(entry);
+            prev = entry;
+        }
+
+        Ok(debug_info)
+    }
+}
+
+fn zigzag(val: i64) -> u64 {
+    ((val << 1) ^ (val >> 63)) as u64
+}
+
+fn unzigzag(val: u64) -> i64 {
+    (val >> 1) as i64 ^ -((val & 1) as i64)
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut val: u64) {
+    loop {
+        let byte = (val & 0x7f) as u8;
+        val >>= 7;
+        if val == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn read_u32(bytes: &[u8], index: &mut usize) -> Result<u32, ModuleError> {
+    u32::try_from(read_varint(bytes, index)?).map_err(|_| ModuleError::InvalidDebugInfo)
+}
+
+fn read_varint(bytes: &[u8], index: &mut usize) -> Result<u64, ModuleError> {
+    let mut val = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *bytes.get(*index).ok_or(ModuleError::Truncated)?;
+        *index += 1;
+        if shift >= 64 {
+            return Err(ModuleError::Truncated);
+        }
+        val |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(val);
+        }
+        shift += 7;
+    }
+}
@@ -0,0 +1,411 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::error::Trap;
+use crate::gc::Value;
+use crate::interpreter::{Instruction, Interpreter, Registers, StackAccess};
+use crate::snapshot::Snapshot;
+
+const CHECKPOINT_INTERVAL: u64 = 1024;
+const HISTORY_LIMIT: u64 = 1 << 16;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(usize),
+    Halted,
+    Trapped(Trap),
+    StartOfHistory,
+    Watchpoint(WatchHit),
+    // The frame a local watchpoint was set in returned, so the watchpoint was removed.
+    WatchpointScope(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn reads(self) -> bool {
+        matches!(self, WatchKind::Read | WatchKind::Access)
+    }
+
+    fn writes(self) -> bool {
+        matches!(self, WatchKind::Write | WatchKind::Access)
+    }
+}
+
+// Locals are addressed like `LoadRelative` does, `offset` slots from the frame
+// pointer of the frame the watchpoint was set in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchTarget {
+    Slot(usize),
+    Local { frame_ptr: usize, offset: i32 },
+}
+
+impl WatchTarget {
+    pub fn slot(self) -> usize {
+        match self {
+            WatchTarget::Slot(slot) => slot,
+            WatchTarget::Local { frame_ptr, offset } => (frame_ptr as u32 as i32).wrapping_add(offset) as u32 as usize,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub kind: WatchKind,
+}
+
+// A watched slot touched by the instruction at `pc`. A read leaves `old` and `new` equal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub pc: usize,
+    pub instruction: Instruction,
+    pub slot: usize,
+    pub kind: WatchKind,
+    pub old: Value,
+    pub new: Value,
+}
+
+// The most recent write to a stack slot, `step` counting from the start of the run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LastWrite {
+    pub step: u64,
+    pub pc: usize,
+    pub old: Value,
+    pub new: Value,
+}
+
+struct StepRecord {
+    pc: usize,
+    ins: Instruction,
+    registers: Registers,
+    access: StackAccess,
+    reversible: bool,
+}
+
+// Drives an interpreter one instruction at a time and remembers enough to run it
+// backwards. Every step logs the registers it started from and the stack slots it
+// wrote, which is all it takes to undo instructions that only touch the stack and
+// registers. Anything else is undone by restoring the closest checkpoint and
+// running forward again, so checkpoints are taken every `CHECKPOINT_INTERVAL`
+// steps. Only the last `HISTORY_LIMIT` steps or so are kept, older ones are
+// dropped a checkpoint interval at a time. Restoring a checkpoint recreates
+// channel objects, host handles to them have to be fetched again afterwards.
+pub struct Debugger {
+    interpreter: Interpreter,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint: usize,
+    // `history` starts at step `first_step`, which always has a checkpoint.
+    history: VecDeque<StepRecord>,
+    first_step: u64,
+    // Step taken at, the snapshot and how far into the journal the run was.
+    checkpoints: Vec<(u64, Snapshot, usize)>,
+    checkpoint_interval: u64,
+    history_limit: u64,
+    halted: bool,
+    trap: Option<Trap>,
+}
+
+impl Debugger {
+    pub fn new(interpreter: Interpreter) -> Debugger {
+        let checkpoints = vec![(0, interpreter.snapshot(), interpreter.journal_position())];
+        Debugger {
+            interpreter,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            next_watchpoint: 0,
+            history: VecDeque::new(),
+            first_step: 0,
+            checkpoints,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            history_limit: HISTORY_LIMIT,
+            halted: false,
+            trap: None,
+        }
+    }
+
+    // Fewer steps between checkpoints make stepping back over heap and object
+    // instructions faster at the cost of memory.
+    pub fn set_checkpoint_interval(&mut self, steps: u64) {
+        self.checkpoint_interval = steps.max(1);
+    }
+
+    // How many steps back the run can go. Memory grows with it for long runs.
+    pub fn set_history_limit(&mut self, steps: u64) {
+        self.history_limit = steps;
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    pub fn into_interpreter(self) -> Interpreter {
+        self.interpreter
+    }
+
+    // Changes the interpreter's state from outside the program. Earlier steps no
+    // longer lead to the edited state, so the history is dropped and starts over.
+    pub fn edit<R>(&mut self, edit: impl FnOnce(&mut Interpreter) -> R) -> R {
+        let result = edit(&mut self.interpreter);
+        self.first_step = self.steps();
+        self.history.clear();
+        self.checkpoints = vec![(self.first_step, self.interpreter.snapshot(), self.interpreter.journal_position())];
+        result
+    }
+
+    pub fn pc(&self) -> usize {
+        self.interpreter.pc()
+    }
+
+    // Instructions executed since the start of the run.
+    pub fn steps(&self) -> u64 {
+        self.first_step + self.history.len() as u64
+    }
+
+    pub fn add_breakpoint(&mut self, offset: usize) {
+        self.breakpoints.insert(offset);
+    }
+
+    pub fn remove_breakpoint(&mut self, offset: usize) -> bool {
+        self.breakpoints.remove(&offset)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // Watches an absolute stack slot, returning the id reported when it is hit.
+    pub fn watch(&mut self, slot: usize, kind: WatchKind) -> usize {
+        self.add_watchpoint(Watchpoint { target: WatchTarget::Slot(slot), kind })
+    }
+
+    // Watches a local of the current frame until that frame returns.
+    pub fn watch_local(&mut self, offset: i32, kind: WatchKind) -> usize {
+        let frame_ptr = self.interpreter.frame_ptr();
+        self.add_watchpoint(Watchpoint { target: WatchTarget::Local { frame_ptr, offset }, kind })
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.remove(&id).is_some()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, Watchpoint)> + '_ {
+        self.watchpoints.iter().map(|(id, watchpoint)| (*id, *watchpoint))
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        if let Some(trap) = &self.trap {
+            return StopReason::Trapped(trap.clone());
+        }
+        if self.halted {
+            return StopReason::Halted;
+        }
+
+        let pc = self.interpreter.pc();
+        let registers = self.interpreter.registers();
+        let ins = self.interpreter.program().code().get(pc).map_or(Instruction::Hlt, |op| Instruction::from(*op));
+        let (result, access) = self.interpreter.single_step_tracked();
+        // A trapped instruction may have done half its work, so only a checkpoint undoes it.
+        let reversible = result.is_ok() && reversible(ins);
+        self.history.push_back(StepRecord { pc, ins, registers, access, reversible });
+
+        if self.steps().is_multiple_of(self.checkpoint_interval) {
+            self.checkpoints.push((self.steps(), self.interpreter.snapshot(), self.interpreter.journal_position()));
+        }
+        self.trim_history();
+        match result {
+            Ok(true) => {
+                self.halted = true;
+                StopReason::Halted
+            }
+            Ok(false) => {
+                if let Some(hit) = self.history.back().and_then(|record| self.watch_hit(record)) {
+                    return StopReason::Watchpoint(hit);
+                }
+                match self.out_of_scope() {
+                    Some(id) => {
+                        self.watchpoints.remove(&id);
+                        StopReason::WatchpointScope(id)
+                    }
+                    None => StopReason::Step,
+                }
+            }
+            Err(trap) => {
+                self.trap = Some(trap.clone());
+                StopReason::Trapped(trap)
+            }
+        }
+    }
+
+    // Runs until the next instruction is a breakpoint, the program halts or traps.
+    pub fn continue_(&mut self) -> StopReason {
+        loop {
+            match self.step() {
+                StopReason::Step if self.breakpoints.contains(&self.pc()) => return StopReason::Breakpoint(self.pc()),
+                StopReason::Step => {}
+                reason => return reason,
+            }
+        }
+    }
+
+    pub fn step_back(&mut self) -> StopReason {
+        let Some(record) = self.history.pop_back() else {
+            return StopReason::StartOfHistory;
+        };
+        self.halted = false;
+        self.trap = None;
+        let step = self.steps();
+        self.checkpoints.retain(|(taken, ..)| *taken <= step);
+
+        let undone = record.reversible && self.interpreter.undo_writes(&record.access.writes).is_ok();
+        if undone {
+            self.interpreter.set_registers(record.registers);
+        } else {
+            self.rewind(step);
+        }
+        StopReason::Step
+    }
+
+    // Steps back until the next instruction is a breakpoint, it is about to touch a
+    // watched slot or the run is back at its start.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        loop {
+            let hit = self.history.back().and_then(|record| self.watch_hit(record));
+            if let StopReason::StartOfHistory = self.step_back() {
+                return StopReason::StartOfHistory;
+            }
+            if let Some(hit) = hit {
+                return StopReason::Watchpoint(hit);
+            }
+            if self.breakpoints.contains(&self.pc()) {
+                return StopReason::Breakpoint(self.pc());
+            }
+            if self.history.is_empty() {
+                return StopReason::StartOfHistory;
+            }
+        }
+    }
+
+    pub fn last_write(&self, slot: usize) -> Option<LastWrite> {
+        self.history.iter().enumerate().rev().find_map(|(step, record)| {
+            record.access.writes.iter().rev().find(|write| write.slot == slot).map(|write| LastWrite {
+                step: self.first_step + step as u64 + 1,
+                pc: record.pc,
+                old: write.old,
+                new: write.new,
+            })
+        })
+    }
+
+    fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.insert(id, watchpoint);
+        id
+    }
+
+    // The first watchpoint `record` touched, looked at while the interpreter is still
+    // just past it. Writes win over reads when an instruction does both.
+    fn watch_hit(&self, record: &StepRecord) -> Option<WatchHit> {
+        self.watchpoints.iter().find_map(|(&id, watchpoint)| {
+            let slot = watchpoint.target.slot();
+            let mut writes = record.access.writes.iter().filter(|write| write.slot == slot);
+            let hit = |kind, old, new| WatchHit { id, pc: record.pc, instruction: record.ins, slot, kind, old, new };
+
+            if watchpoint.kind.writes() {
+                if let Some(first) = writes.next() {
+                    let last = writes.next_back().unwrap_or(first);
+                    return Some(hit(WatchKind::Write, first.old, last.new));
+                }
+            }
+            if watchpoint.kind.reads() && record.access.reads.contains(&slot) {
+                let val = match record.access.writes.iter().find(|write| write.slot == slot) {
+                    Some(write) => write.old,
+                    None => self.interpreter.stack_value(slot).ok()?,
+                };
+                return Some(hit(WatchKind::Read, val, val));
+            }
+            None
+        })
+    }
+
+    // A local watchpoint whose frame has returned. Frames deeper than the caller
+    // sit at lower slots, the top level has no frame at all.
+    fn out_of_scope(&self) -> Option<usize> {
+        let current = self.interpreter.frame_ptr();
+        self.watchpoints.iter().find_map(|(&id, watchpoint)| match watchpoint.target {
+            WatchTarget::Local { frame_ptr, .. } if frame_ptr != 0 && (current == 0 || current > frame_ptr) => Some(id),
+            _ => None,
+        })
+    }
+
+    // Drops the oldest steps up to the next checkpoint while over the limit, so
+    // the history still starts at a checkpoint.
+    fn trim_history(&mut self) {
+        while self.history.len() as u64 > self.history_limit && self.checkpoints.len() > 1 {
+            let next = self.checkpoints[1].0;
+            self.history.drain(..(next - self.first_step) as usize);
+            self.first_step = next;
+            self.checkpoints.remove(0);
+        }
+    }
+
+    // Restores the last checkpoint, which is at or before `step`, and runs forward to it.
+    fn rewind(&mut self, step: u64) {
+        let Some((taken, snapshot, position)) = self.checkpoints.last() else {
+            return;
+        };
+        let (taken, position) = (*taken, *position);
+        if self.interpreter.load_snapshot(snapshot).is_err() {
+            return;
+        }
+        self.interpreter.rerun(position, |interpreter| {
+            for _ in taken..step {
+                if interpreter.single_step().is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+// Instructions whose only effects are on stack slots and registers. `DerefAssign`
+// and `DerefAssignRelative` may store to the heap and are left to checkpoints.
+fn reversible(ins: Instruction) -> bool {
+    use Instruction::*;
+    matches!(
+        ins,
+        Nop | I32Add | I32Sub | I32Mul | I32Div | Push | Pop | Call | Ret | PushReg | PopReg |
+        Store | Load | StoreRelative | LoadRelative | StackAdd | Deref | Lea | Cmp | Jmp | Jz | Jnz | Greater | GreaterEqual | Lesser |
+        LesserEqual | Equal | NotEqual | GetField | GetElem | ArrayLen | Enter | Leave | RetN |
+        TailCall | CallIndirect | LoadUpvalue | CallClosure | Throw | CoStatus
+    )
+}
@@ -0,0 +1,142 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::fmt::Display;
+
+use crate::debug_info::SourceLocation;
+use crate::gc::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmError {
+    StackOverflow,
+    StackUnderflow,
+    StackOutOfBounds(i32),
+    CodeOutOfBounds(usize),
+    HeapExhausted(usize),
+    HeapOutOfBounds { address: i32, width: usize },
+    InvalidFree(i32),
+    DanglingReference(u32),
+    ExpectedReference(i32),
+    TypeMismatch { expected: &'static str, found: String },
+    IndexOutOfBounds { object: u32, index: i32 },
+    NegativeLength(i32),
+    DivideByZero,
+    InvalidRegister(u8),
+    InvalidCompilerCall(i32),
+    InvalidFunction(i32),
+    InvalidConstant(i32),
+    InvalidInstruction(u8),
+    Uncaught(Value),
+    CoroutineNotSuspended(u32),
+    NotInCoroutine,
+    NoScheduler,
+    InvalidTask(i32),
+    JoinFailed(i32),
+    Deadlock,
+    WouldBlock(u32),
+    OutOfFuel,
+    Diverged { event: usize, expected: String, found: String },
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::StackOutOfBounds(index) => write!(f, "stack access out of bounds : {}", index),
+            VmError::CodeOutOfBounds(index) => write!(f, "instruction pointer out of bounds : {}", index),
+            VmError::HeapExhausted(size) => write!(f, "heap exhausted while allocating {} bytes", size),
+            VmError::HeapOutOfBounds { address, width } => write!(f, "heap access out of bounds : {:#x} ({} bytes)", *address as u32, width),
+            VmError::InvalidFree(address) => write!(f, "address is not an allocated block : {:#x}", *address as u32),
+            VmError::DanglingReference(index) => write!(f, "dangling object reference : #{}", index),
+            VmError::ExpectedReference(val) => write!(f, "expected an object reference but found the integer : {}", val),
+            VmError::TypeMismatch { expected, found } => write!(f, "expected {} but found : {}", expected, found),
+            VmError::IndexOutOfBounds { object, index } => write!(f, "index out of bounds : #{}[{}]", object, index),
+            VmError::NegativeLength(len) => write!(f, "negative length : {}", len),
+            VmError::DivideByZero => write!(f, "division by zero"),
+            VmError::InvalidRegister(reg) => write!(f, "register does not exist : {}", reg),
+            VmError::InvalidCompilerCall(function) => write!(f, "compiler call with index does not exist : [{}]", function),
+            VmError::InvalidFunction(function) => write!(f, "function with index does not exist : [{}]", function),
+            VmError::InvalidConstant(index) => write!(f, "constant with index does not exist : [{}]", index),
+            VmError::InvalidInstruction(op) => write!(f, "invalid instruction with op code of : {}", op),
+            VmError::Uncaught(val) => write!(f, "uncaught exception : {}", val),
+            VmError::CoroutineNotSuspended(index) => write!(f, "coroutine is not suspended : #{}", index),
+            VmError::NotInCoroutine => write!(f, "yield outside of a coroutine"),
+            VmError::NoScheduler => write!(f, "task instructions need a scheduler"),
+            VmError::InvalidTask(task) => write!(f, "task with id does not exist : [{}]", task),
+            VmError::JoinFailed(task) => write!(f, "joined task failed : [{}]", task),
+            VmError::Deadlock => write!(f, "every remaining task is waiting on another"),
+            VmError::WouldBlock(channel) => write!(f, "receive from empty channel : #{}", channel),
+            VmError::OutOfFuel => write!(f, "out of fuel"),
+            VmError::Diverged { event, expected, found } => {
+                write!(f, "replay diverged at event {} : the log has {} but the run did {}", event, expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BacktraceFrame {
+    pub pc: usize,
+    // The frame's locals sit at and below it, 0 outside any call.
+    pub frame_ptr: usize,
+    pub function: Option<String>,
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Backtrace {
+    pub frames: Vec<BacktraceFrame>,
+}
+
+impl Display for Backtrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (depth, frame) in self.frames.iter().enumerate() {
+            write!(f, "  {}: at {}", depth, frame.pc)?;
+            if let Some(function) = &frame.function {
+                write!(f, " in {}", function)?;
+            }
+            if let Some(location) = &frame.location {
+                write!(f, " ({})", location)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// A `VmError` together with where it happened, the innermost frame first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trap {
+    pub error: VmError,
+    pub backtrace: Backtrace,
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "error: {}", self.error)?;
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+impl std::error::Error for Trap {}
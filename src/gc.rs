@@ -0,0 +1,342 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::error::VmError;
+use crate::interpreter::Coroutine;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+// Values that can live in a stack slot or inside an object. References are
+// tracked separately from plain integers so the collector never has to guess.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Value {
+    Int(i32),
+    Ref(u32),
+}
+
+impl Value {
+    pub fn raw(self) -> i32 {
+        match self {
+            Value::Int(val) => val,
+            Value::Ref(index) => index as i32,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(val) => write!(f, "{}", val),
+            Value::Ref(index) => write!(f, "#{}", index),
+        }
+    }
+}
+
+// An unbounded queue shared between tasks. Clones are handles to the same queue,
+// which is how the host talks to a script without touching its stack.
+#[derive(Clone, Default)]
+pub struct Channel {
+    queue: Arc<Mutex<VecDeque<Value>>>,
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        Channel::default()
+    }
+
+    // Host code can only send plain integers, references make no sense outside the VM.
+    pub fn send(&self, val: i32) {
+        self.lock().push_back(Value::Int(val));
+    }
+
+    pub fn try_recv(&self) -> Option<Value> {
+        self.lock().pop_front()
+    }
+
+    pub fn drain(&self) -> Vec<Value> {
+        self.lock().drain(..).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, VecDeque<Value>> {
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.queue, &other.queue)
+    }
+}
+
+impl Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Channel").field(&*self.lock()).finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Object {
+    String(String),
+    Bytes(Vec<u8>),
+    Array(Vec<Value>),
+    Record(Vec<Value>),
+    Closure { function: u32, upvalues: Vec<Value> },
+    Coroutine(Box<Coroutine>),
+    Channel(Channel),
+}
+
+impl Object {
+    pub fn len(&self) -> usize {
+        match self {
+            Object::String(str) => str.len(),
+            Object::Bytes(bytes) => bytes.len(),
+            Object::Array(elems) => elems.len(),
+            Object::Record(fields) => fields.len(),
+            Object::Closure { upvalues, .. } => upvalues.len(),
+            Object::Coroutine(_) => 0,
+            Object::Channel(channel) => channel.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Channels are restored with their queued values but as a new queue, so handles
+    // the host held before the snapshot have to be fetched again.
+    pub(crate) fn encode(&self, out: &mut Writer) {
+        match self {
+            Object::String(str) => {
+                out.u8(0);
+                out.blob(str.as_bytes());
+            }
+            Object::Bytes(bytes) => {
+                out.u8(1);
+                out.blob(bytes);
+            }
+            Object::Array(elems) => {
+                out.u8(2);
+                out.values(elems.iter());
+            }
+            Object::Record(fields) => {
+                out.u8(3);
+                out.values(fields.iter());
+            }
+            Object::Closure { function, upvalues } => {
+                out.u8(4);
+                out.u32(*function);
+                out.values(upvalues.iter());
+            }
+            Object::Coroutine(coroutine) => {
+                out.u8(5);
+                coroutine.encode(out);
+            }
+            Object::Channel(channel) => {
+                out.u8(6);
+                out.values(channel.lock().iter());
+            }
+        }
+    }
+
+    pub(crate) fn decode(input: &mut Reader) -> Result<Object, SnapshotError> {
+        Ok(match input.u8()? {
+            0 => Object::String(
+                String::from_utf8(input.blob()?.to_vec()).map_err(|_| SnapshotError::Corrupt("string is not valid utf-8"))?
+            ),
+            1 => Object::Bytes(input.blob()?.to_vec()),
+            2 => Object::Array(input.values()?),
+            3 => Object::Record(input.values()?),
+            4 => Object::Closure { function: input.u32()?, upvalues: input.values()? },
+            5 => Object::Coroutine(Box::new(Coroutine::decode(input)?)),
+            6 => {
+                let channel = Channel::new();
+                channel.lock().extend(input.values()?);
+                Object::Channel(channel)
+            }
+            _ => return Err(SnapshotError::Corrupt("invalid object tag")),
+        })
+    }
+
+    fn children(&self) -> Vec<u32> {
+        let values: &[Value] = match self {
+            Object::String(_) | Object::Bytes(_) => &[],
+            Object::Array(values) | Object::Record(values) => values,
+            Object::Closure { upvalues, .. } => upvalues,
+            Object::Coroutine(coroutine) => return coroutine.roots().collect(),
+            Object::Channel(channel) => return channel.lock().iter().filter_map(|val| match val {
+                Value::Ref(index) => Some(*index),
+                Value::Int(_) => None,
+            }).collect(),
+        };
+        values.iter().filter_map(|val| match val {
+            Value::Ref(index) => Some(*index),
+            Value::Int(_) => None,
+        }).collect()
+    }
+}
+
+struct GcBox {
+    object: Object,
+    marked: bool,
+}
+
+const GC_INITIAL_THRESHOLD: usize = 256;
+
+pub struct ObjectHeap {
+    objects: Vec<Option<GcBox>>,
+    free: Vec<u32>,
+    live: usize,
+    threshold: usize,
+}
+
+impl ObjectHeap {
+    pub fn new() -> ObjectHeap {
+        ObjectHeap { objects: Vec::new(), free: Vec::new(), live: 0, threshold: GC_INITIAL_THRESHOLD }
+    }
+
+    pub fn live(&self) -> usize {
+        self.live
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.live >= self.threshold
+    }
+
+    pub fn alloc(&mut self, object: Object) -> Value {
+        let gc_box = Some(GcBox { object, marked: false });
+        self.live += 1;
+        match self.free.pop() {
+            Some(index) => {
+                self.objects[index as usize] = gc_box;
+                Value::Ref(index)
+            }
+            None => {
+                self.objects.push(gc_box);
+                Value::Ref(self.objects.len() as u32 - 1)
+            }
+        }
+    }
+
+    pub fn get(&self, val: Value) -> Result<&Object, VmError> {
+        let index = Self::index(val)?;
+        match self.objects.get(index as usize) {
+            Some(Some(gc_box)) => Ok(&gc_box.object),
+            _ => Err(VmError::DanglingReference(index)),
+        }
+    }
+
+    pub fn get_mut(&mut self, val: Value) -> Result<&mut Object, VmError> {
+        let index = Self::index(val)?;
+        match self.objects.get_mut(index as usize) {
+            Some(Some(gc_box)) => Ok(&mut gc_box.object),
+            _ => Err(VmError::DanglingReference(index)),
+        }
+    }
+
+    pub fn collect(&mut self, roots: impl Iterator<Item = u32>) {
+        let mut worklist: Vec<u32> = roots.collect();
+        while let Some(index) = worklist.pop() {
+            if let Some(Some(gc_box)) = self.objects.get_mut(index as usize) {
+                if !gc_box.marked {
+                    gc_box.marked = true;
+                    worklist.extend(gc_box.object.children());
+                }
+            }
+        }
+
+        for (index, slot) in self.objects.iter_mut().enumerate() {
+            match slot {
+                Some(gc_box) if gc_box.marked => gc_box.marked = false,
+                Some(_) => {
+                    *slot = None;
+                    self.free.push(index as u32);
+                    self.live -= 1;
+                }
+                None => {}
+            }
+        }
+
+        self.threshold = (self.live * 2).max(GC_INITIAL_THRESHOLD);
+    }
+
+    pub(crate) fn encode(&self, out: &mut Writer) {
+        out.usize(self.objects.len());
+        for slot in &self.objects {
+            match slot {
+                Some(gc_box) => {
+                    out.bool(true);
+                    gc_box.object.encode(out);
+                }
+                None => out.bool(false),
+            }
+        }
+        out.usize(self.free.len());
+        for index in &self.free {
+            out.u32(*index);
+        }
+        out.usize(self.threshold);
+    }
+
+    pub(crate) fn decode(input: &mut Reader) -> Result<ObjectHeap, SnapshotError> {
+        let mut heap = ObjectHeap::new();
+        for _ in 0..input.len(1)? {
+            if input.bool()? {
+                heap.objects.push(Some(GcBox { object: Object::decode(input)?, marked: false }));
+                heap.live += 1;
+            } else {
+                heap.objects.push(None);
+            }
+        }
+        for _ in 0..input.len(4)? {
+            let index = input.u32()?;
+            if !matches!(heap.objects.get(index as usize), Some(None)) {
+                return Err(SnapshotError::Corrupt("free list names a live object"));
+            }
+            heap.free.push(index);
+        }
+        heap.threshold = input.usize()?;
+        Ok(heap)
+    }
+
+    fn index(val: Value) -> Result<u32, VmError> {
+        match val {
+            Value::Ref(index) => Ok(index),
+            Value::Int(val) => Err(VmError::ExpectedReference(val)),
+        }
+    }
+}
+
+impl Default for ObjectHeap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
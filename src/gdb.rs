@@ -0,0 +1,485 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+use crate::debugger::{Debugger, StopReason, WatchKind};
+use crate::error::VmError;
+use crate::gc::Value;
+use crate::heap::is_heap_address;
+use crate::interpreter::{Flags, Interpreter};
+
+// Instructions run between looks at the connection while continuing, which is
+// what lets gdb interrupt an endless loop.
+const SLICE: usize = 4096;
+const PACKET_SIZE: usize = 0x4000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// The registers `g` and `p` report, in this order, each 32 bits little endian.
+// The flag fields are generated from `Flags::fields`.
+pub fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>\n",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n",
+        "<target version=\"1.0\">\n",
+        "  <feature name=\"org.bytecode.vm\">\n",
+        "    <flags id=\"vm_flags\" size=\"4\">\n",
+    ));
+    for (bit, (name, _)) in Flags::new().fields().iter().enumerate() {
+        let _ = writeln!(xml, "      <field name=\"{}\" start=\"{}\" end=\"{}\"/>", name, bit, bit);
+    }
+    xml.push_str(concat!(
+        "    </flags>\n",
+        "    <reg name=\"ptr\" bitsize=\"32\" type=\"code_ptr\" regnum=\"0\"/>\n",
+        "    <reg name=\"stack_ptr\" bitsize=\"32\" type=\"data_ptr\"/>\n",
+        "    <reg name=\"frame_ptr\" bitsize=\"32\" type=\"data_ptr\"/>\n",
+        "    <reg name=\"flags\" bitsize=\"32\" type=\"vm_flags\"/>\n",
+        "  </feature>\n",
+        "</target>\n",
+    ));
+    xml
+}
+
+// What arrives from gdb, a packet whose checksum did not match is `Corrupt`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Packet(Vec<u8>),
+    Corrupt,
+    Interrupt,
+    Ack,
+    Nack,
+}
+
+// Reads the next packet or control byte, undoing `}` escapes. Returns None once
+// the connection is closed.
+pub fn read_input(input: &mut impl Read) -> io::Result<Option<Input>> {
+    loop {
+        match next_byte(input)? {
+            None => return Ok(None),
+            Some(b'+') => return Ok(Some(Input::Ack)),
+            Some(b'-') => return Ok(Some(Input::Nack)),
+            Some(0x03) => return Ok(Some(Input::Interrupt)),
+            Some(b'$') => break,
+            Some(_) => {}
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut sum = 0u8;
+    loop {
+        let byte = next_byte(input)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+        if byte == b'#' {
+            break;
+        }
+        sum = sum.wrapping_add(byte);
+        if byte == b'}' {
+            let escaped = next_byte(input)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            sum = sum.wrapping_add(escaped);
+            data.push(escaped ^ 0x20);
+        } else {
+            data.push(byte);
+        }
+    }
+    let mut checksum = [0; 2];
+    input.read_exact(&mut checksum)?;
+    let checksum = std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+    Ok(Some(if checksum == Some(sum) { Input::Packet(data) } else { Input::Corrupt }))
+}
+
+fn next_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+pub fn write_packet(output: &mut impl Write, data: &str) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    for &byte in data.as_bytes() {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            packet.extend([b'}', byte ^ 0x20]);
+        } else {
+            packet.push(byte);
+        }
+    }
+    let sum = packet[1..].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    write!(packet, "#{:02x}", sum)?;
+    output.write_all(&packet)?;
+    output.flush()
+}
+
+// Serves one gdb connection until it detaches, kills the program or closes the
+// connection. Packets are read on their own thread so an interrupt still arrives
+// while the program runs.
+pub fn serve(stub: &mut GdbStub, input: impl Read + Send + 'static, mut output: impl Write) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut input = BufReader::new(input);
+        loop {
+            let message = read_input(&mut input).transpose();
+            let last = !matches!(message, Some(Ok(_)));
+            if message.is_some_and(|message| sender.send(message).is_err()) || last {
+                break;
+            }
+        }
+    });
+
+    while !stub.is_finished() {
+        let input = if stub.is_running() {
+            match receiver.try_recv() {
+                Ok(input) => input?,
+                Err(TryRecvError::Empty) => {
+                    if let Some(reply) = stub.poll() {
+                        write_packet(&mut output, &reply)?;
+                    }
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match receiver.recv() {
+                Ok(input) => input?,
+                Err(_) => break,
+            }
+        };
+
+        let reply = match input {
+            Input::Packet(data) => {
+                if !stub.no_ack {
+                    output.write_all(b"+")?;
+                }
+                stub.handle(&data)
+            }
+            Input::Corrupt if !stub.no_ack => {
+                output.write_all(b"-")?;
+                None
+            }
+            Input::Interrupt => stub.interrupt(),
+            Input::Corrupt | Input::Ack | Input::Nack => None,
+        };
+        match reply {
+            Some(reply) => write_packet(&mut output, &reply)?,
+            None => output.flush()?,
+        }
+    }
+    Ok(())
+}
+
+// The target side of the gdb remote serial protocol for one interpreter.
+//
+// gdb sees a single thread and a byte addressed data space: stack slot `n` is the
+// four bytes at `4 * n`, and heap addresses, which carry `HEAP_TAG`, are used as
+// they are. Breakpoints and `ptr` are code offsets, which live in a space of their
+// own, so breakpoints are only ever set through `Z0` and never written into memory.
+pub struct GdbStub {
+    debugger: Debugger,
+    watches: BTreeMap<(u8, u32, u32), Vec<usize>>,
+    last_stop: String,
+    running: bool,
+    no_ack: bool,
+    finished: bool,
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger) -> GdbStub {
+        GdbStub {
+            debugger,
+            watches: BTreeMap::new(),
+            last_stop: format!("S{:02x}", SIGTRAP),
+            running: false,
+            no_ack: false,
+            finished: false,
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.debugger
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    // Answers one packet. Continuing answers later, from `poll` or `interrupt`,
+    // and `k` is never answered.
+    pub fn handle(&mut self, packet: &[u8]) -> Option<String> {
+        let packet = String::from_utf8_lossy(packet);
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => self.last_stop.clone(),
+            Some(b'g') => self.registers().iter().map(|reg| le_hex(*reg)).collect(),
+            Some(b'p') => match u32::from_str_radix(&packet[1..], 16).ok().and_then(|reg| self.registers().get(reg as usize).copied()) {
+                Some(reg) => le_hex(reg),
+                None => error(),
+            },
+            Some(b'm') => self.read_memory(&packet[1..]).unwrap_or_else(error),
+            Some(b'M') => self.write_memory(&packet[1..]).map_or_else(error, |_| "OK".to_string()),
+            Some(b'Z' | b'z') => self.breakpoint(&packet),
+            Some(b'c') => return self.resume(),
+            Some(b's') => self.step(),
+            Some(b'b') if packet.as_ref() == "bs" => {
+                let reason = self.debugger.step_back();
+                self.stop_reply(reason)
+            }
+            Some(b'b') if packet.as_ref() == "bc" => {
+                let reason = self.debugger.reverse_continue();
+                self.stop_reply(reason)
+            }
+            Some(b'v') => return self.v_packet(&packet),
+            Some(b'q' | b'Q') => self.query(&packet),
+            Some(b'H' | b'T') => "OK".to_string(),
+            Some(b'D') => {
+                self.finished = true;
+                "OK".to_string()
+            }
+            Some(b'k') => {
+                self.finished = true;
+                return None;
+            }
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    // Runs a continued program a slice further, returning its stop reply once it stops.
+    pub fn poll(&mut self) -> Option<String> {
+        if !self.running {
+            return None;
+        }
+        for _ in 0..SLICE {
+            let reason = match self.debugger.step() {
+                StopReason::Step if self.debugger.breakpoints().any(|offset| offset == self.debugger.pc()) => {
+                    StopReason::Breakpoint(self.debugger.pc())
+                }
+                StopReason::Step => continue,
+                reason => reason,
+            };
+            self.running = false;
+            return Some(self.stop_reply(reason));
+        }
+        None
+    }
+
+    pub fn interrupt(&mut self) -> Option<String> {
+        if !self.running {
+            return None;
+        }
+        self.running = false;
+        self.last_stop = format!("S{:02x}", SIGINT);
+        Some(self.last_stop.clone())
+    }
+
+    fn resume(&mut self) -> Option<String> {
+        self.running = true;
+        self.poll()
+    }
+
+    fn step(&mut self) -> String {
+        let reason = self.debugger.step();
+        self.stop_reply(reason)
+    }
+
+    fn stop_reply(&mut self, reason: StopReason) -> String {
+        self.last_stop = match reason {
+            StopReason::Step | StopReason::WatchpointScope(_) => format!("S{:02x}", SIGTRAP),
+            StopReason::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::StartOfHistory => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            StopReason::Watchpoint(hit) => {
+                let kind = self.watches.iter()
+                    .find(|(_, ids)| ids.contains(&hit.id))
+                    .map_or(b'2', |((kind, _, _), _)| *kind);
+                let name = match kind {
+                    b'3' => "rwatch",
+                    b'4' => "awatch",
+                    _ => "watch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.slot * 4)
+            }
+            StopReason::Halted => "W00".to_string(),
+            StopReason::Trapped(_) => format!("S{:02x}", SIGSEGV),
+        };
+        self.last_stop.clone()
+    }
+
+    fn registers(&self) -> [u32; 4] {
+        let registers = self.debugger.interpreter().registers();
+        [registers.ptr as u32, registers.stack_ptr as u32, registers.frame_ptr as u32, registers.flags.to_bits()]
+    }
+
+    // Reads as much of the range as is mapped, failing only when none of it is.
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, len) = parse_range(args)?;
+        let len = len.min(PACKET_SIZE as u32 / 2);
+        let vm = self.debugger.interpreter();
+        let mut hex = String::new();
+        for byte in (0..len).map_while(|i| read_byte(vm, address.wrapping_add(i))) {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        (len == 0 || !hex.is_empty()).then_some(hex)
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<()> {
+        let (range, data) = args.split_once(':')?;
+        let (address, len) = parse_range(range)?;
+        let bytes = parse_hex(data)?;
+        if bytes.len() != len as usize {
+            return None;
+        }
+        self.debugger.edit(|vm| {
+            bytes.iter().enumerate().try_for_each(|(i, byte)| write_byte(vm, address.wrapping_add(i as u32), *byte))
+        }).ok()
+    }
+
+    // `Z0`/`Z1` are breakpoints on code offsets, `Z2` to `Z4` watch the stack slots
+    // a data range covers. Heap watchpoints are left to gdb, which single-steps.
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let insert = packet.starts_with('Z');
+        let mut fields = packet[1..].splitn(3, ',');
+        let kind = fields.next().and_then(|kind| kind.bytes().next());
+        let address = fields.next().and_then(|address| u32::from_str_radix(address, 16).ok());
+        let len = fields.next().and_then(|len| u32::from_str_radix(len.split(';').next()?, 16).ok());
+        let (Some(kind), Some(address), Some(len)) = (kind, address, len) else {
+            return error();
+        };
+
+        match kind {
+            b'0' | b'1' if insert => self.debugger.add_breakpoint(address as usize),
+            b'0' | b'1' => {
+                self.debugger.remove_breakpoint(address as usize);
+            }
+            b'2'..=b'4' if is_heap_address(address as i32) => return String::new(),
+            b'2'..=b'4' if insert => {
+                let watch = match kind {
+                    b'2' => WatchKind::Write,
+                    b'3' => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let slots = address / 4..address.saturating_add(len.max(1)).div_ceil(4);
+                let ids = slots.map(|slot| self.debugger.watch(slot as usize, watch)).collect();
+                self.watches.insert((kind, address, len), ids);
+            }
+            b'2'..=b'4' => {
+                for id in self.watches.remove(&(kind, address, len)).unwrap_or_default() {
+                    self.debugger.remove_watchpoint(id);
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn v_packet(&mut self, packet: &str) -> Option<String> {
+        if packet == "vCont?" {
+            return Some("vCont;c;C;s;S".to_string());
+        }
+        // There is only one thread, so the first action is the one that applies.
+        match packet.strip_prefix("vCont;").and_then(|actions| actions.bytes().next()) {
+            Some(b'c' | b'C') => self.resume(),
+            Some(b's' | b'S') => Some(self.step()),
+            _ => Some(String::new()),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+;QStartNoAckMode+;ReverseStep+;ReverseContinue+", PACKET_SIZE);
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = parse_range(range) else {
+                return error();
+            };
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = start.saturating_add(len as usize).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &xml[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ if packet.starts_with("qSymbol") => "OK".to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+fn read_byte(vm: &Interpreter, address: u32) -> Option<u8> {
+    if is_heap_address(address as i32) {
+        return vm.heap().load(address as i32, 1).ok().map(|byte| byte as u8);
+    }
+    let val = vm.stack_value(address as usize / 4).ok()?;
+    Some(val.raw().to_le_bytes()[address as usize % 4])
+}
+
+// Writing a stack slot turns a reference in it into a plain integer.
+fn write_byte(vm: &mut Interpreter, address: u32, byte: u8) -> Result<(), VmError> {
+    if is_heap_address(address as i32) {
+        return vm.heap_mut().store(address as i32, 1, byte as u32);
+    }
+    let slot = address as usize / 4;
+    let mut bytes = vm.stack_value(slot)?.raw().to_le_bytes();
+    bytes[address as usize % 4] = byte;
+    vm.set_stack_value(slot, Value::Int(i32::from_le_bytes(bytes)))
+}
+
+// An `address,length` pair in hex.
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (address, len) = range.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(len, 16).ok()?))
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    let bytes = hex.as_bytes();
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    bytes.chunks(2).map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()).collect()
+}
+
+fn le_hex(val: u32) -> String {
+    val.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn error() -> String {
+    "E01".to_string()
+}
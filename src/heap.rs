@@ -0,0 +1,206 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::BTreeMap;
+
+use crate::error::VmError;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+// Addresses with this bit set point into the heap, everything else is a stack index.
+pub const HEAP_TAG: u32 = 0x4000_0000;
+pub const HEAP_MAX: usize = (HEAP_TAG - 1) as usize;
+
+// Offset 0 is never handed out so a tagged null can not alias a live block.
+const HEAP_BASE: usize = 8;
+const HEAP_ALIGN: usize = 4;
+
+pub fn is_heap_address(address: i32) -> bool {
+    address as u32 & HEAP_TAG != 0
+}
+
+pub fn heap_address(offset: usize) -> i32 {
+    (offset as u32 | HEAP_TAG) as i32
+}
+
+pub fn heap_offset(address: i32) -> usize {
+    (address as u32 & !HEAP_TAG) as usize
+}
+
+pub struct Heap {
+    memory: Vec<u8>,
+    blocks: BTreeMap<usize, usize>,
+    free: BTreeMap<usize, usize>,
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap { memory: vec![0; HEAP_BASE], blocks: BTreeMap::new(), free: BTreeMap::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn alloc(&mut self, size: usize) -> Result<i32, VmError> {
+        let size = size.max(1).next_multiple_of(HEAP_ALIGN);
+
+        let found = self.free.iter().find(|(_, &len)| len >= size).map(|(&start, &len)| (start, len));
+        let start = match found {
+            Some((start, len)) => {
+                self.free.remove(&start);
+                if len > size {
+                    self.free.insert(start + size, len - size);
+                }
+                start
+            }
+            None => {
+                let start = self.memory.len();
+                if start + size > HEAP_MAX {
+                    return Err(VmError::HeapExhausted(size));
+                }
+                self.memory.resize(start + size, 0);
+                start
+            }
+        };
+
+        self.memory[start..start + size].fill(0);
+        self.blocks.insert(start, size);
+        Ok(heap_address(start))
+    }
+
+    pub fn free(&mut self, address: i32) -> Result<(), VmError> {
+        let start = heap_offset(address);
+        let size = match self.blocks.remove(&start) {
+            Some(size) if is_heap_address(address) => size,
+            _ => return Err(VmError::InvalidFree(address)),
+        };
+        self.release(start, size);
+        Ok(())
+    }
+
+    pub fn realloc(&mut self, address: i32, size: usize) -> Result<i32, VmError> {
+        let start = heap_offset(address);
+        let old_size = match self.blocks.get(&start) {
+            Some(&size) if is_heap_address(address) => size,
+            _ => return Err(VmError::InvalidFree(address)),
+        };
+
+        let new_address = self.alloc(size)?;
+        let new_start = heap_offset(new_address);
+        let copied = old_size.min(self.blocks[&new_start]);
+        self.memory.copy_within(start..start + copied, new_start);
+        self.free(address)?;
+        Ok(new_address)
+    }
+
+    pub fn load(&self, address: i32, width: usize) -> Result<u32, VmError> {
+        let start = self.check(address, width)?;
+        let mut val = 0u32;
+        for (i, byte) in self.memory[start..start + width].iter().enumerate() {
+            val |= (*byte as u32) << (i * 8);
+        }
+        Ok(val)
+    }
+
+    pub fn store(&mut self, address: i32, width: usize, val: u32) -> Result<(), VmError> {
+        let start = self.check(address, width)?;
+        for (i, byte) in self.memory[start..start + width].iter_mut().enumerate() {
+            *byte = (val >> (i * 8)) as u8;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn encode(&self, out: &mut Writer) {
+        out.blob(&self.memory);
+        for map in [&self.blocks, &self.free] {
+            out.usize(map.len());
+            for (&start, &size) in map {
+                out.usize(start);
+                out.usize(size);
+            }
+        }
+    }
+
+    pub(crate) fn decode(input: &mut Reader) -> Result<Heap, SnapshotError> {
+        let memory = input.blob()?.to_vec();
+        if memory.len() < HEAP_BASE {
+            return Err(SnapshotError::Corrupt("heap is shorter than its reserved base"));
+        }
+        let mut maps = [BTreeMap::new(), BTreeMap::new()];
+        for map in &mut maps {
+            for _ in 0..input.len(16)? {
+                let (start, size) = (input.usize()?, input.usize()?);
+                if start < HEAP_BASE || start.checked_add(size).is_none_or(|end| end > memory.len()) {
+                    return Err(SnapshotError::Corrupt("heap block out of bounds"));
+                }
+                map.insert(start, size);
+            }
+        }
+        // A byte belongs to at most one block, live or free.
+        let mut ranges: Vec<_> = maps.iter().flatten().collect();
+        ranges.sort_unstable();
+        if ranges.windows(2).any(|pair| pair[0].0 + pair[0].1 > *pair[1].0) {
+            return Err(SnapshotError::Corrupt("heap blocks overlap"));
+        }
+        let [blocks, free] = maps;
+        Ok(Heap { memory, blocks, free })
+    }
+
+    fn check(&self, address: i32, width: usize) -> Result<usize, VmError> {
+        let offset = heap_offset(address);
+        match self.blocks.range(..=offset).next_back() {
+            Some((&start, &size)) if is_heap_address(address) && offset + width <= start + size => Ok(offset),
+            _ => Err(VmError::HeapOutOfBounds { address, width }),
+        }
+    }
+
+    fn release(&mut self, start: usize, size: usize) {
+        let mut start = start;
+        let mut size = size;
+
+        if let Some((&prev, &prev_size)) = self.free.range(..start).next_back() {
+            if prev + prev_size == start {
+                self.free.remove(&prev);
+                start = prev;
+                size += prev_size;
+            }
+        }
+        if let Some(next_size) = self.free.remove(&(start + size)) {
+            size += next_size;
+        }
+
+        if start + size == self.memory.len() {
+            self.memory.truncate(start);
+        } else {
+            self.free.insert(start, size);
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
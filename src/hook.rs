@@ -0,0 +1,209 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::any::Any;
+use std::io::{Stderr, Write};
+
+use crate::error::Trap;
+use crate::gc::Value;
+use crate::interpreter::{operand_values, CompilerCall, Instruction, Interpreter};
+
+// Callbacks an `Interpreter` makes while it runs, every one of them a no-op by
+// default. `pc` is always the offset of the instruction being executed. Calls,
+// returns and host calls are reported after `after_instruction`, once the
+// instruction has completed; an instruction that traps only gets `on_trap`.
+pub trait ExecutionHook: Any + Send {
+    fn before_instruction(&mut self, _vm: &Interpreter, _pc: usize, _ins: Instruction) {}
+
+    fn after_instruction(&mut self, _vm: &Interpreter, _pc: usize, _ins: Instruction) {}
+
+    // `to` is the first instruction of the callee.
+    fn on_call(&mut self, _vm: &Interpreter, _pc: usize, _to: usize) {}
+
+    // `to` is the return address in the caller.
+    fn on_return(&mut self, _vm: &Interpreter, _pc: usize, _to: usize) {}
+
+    fn on_host_call(&mut self, _vm: &Interpreter, _pc: usize, _call: CompilerCall) {}
+
+    fn on_trap(&mut self, _vm: &Interpreter, _trap: &Trap) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameEvent {
+    // A frame whose code starts at the given offset.
+    Enter(usize),
+    Exit,
+}
+
+struct TrackedFrame {
+    frame_ptr: usize,
+    coroutine: Option<Value>,
+}
+
+// Follows the call frames of a run for hooks that attribute work to functions.
+// Hand it the callbacks it has methods for and apply the events it returns in
+// order. The first instruction enters a frame for the code the run starts in,
+// which only ends with the run. Coroutines switch stacks without a call or a
+// return: the frames a coroutine entered end when it yields or finishes, and
+// after the next resume its code counts towards the frame that resumed it until
+// it calls again.
+#[derive(Default)]
+pub struct FrameTracker {
+    frames: Vec<TrackedFrame>,
+    last_ins: Option<Instruction>,
+}
+
+impl FrameTracker {
+    pub fn new() -> FrameTracker {
+        FrameTracker::default()
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn before_instruction(&mut self, vm: &Interpreter, pc: usize, ins: Instruction) -> Vec<FrameEvent> {
+        self.last_ins = Some(ins);
+        let mut events = self.leave_coroutines(vm);
+        if self.frames.is_empty() {
+            events.push(self.enter(vm, pc));
+        }
+        events
+    }
+
+    // A `Throw` can unwind any number of frames, it ends every one below the handler's.
+    pub fn after_instruction(&mut self, vm: &Interpreter, ins: Instruction) -> Vec<FrameEvent> {
+        let mut events = self.leave_coroutines(vm);
+        if let Instruction::Throw = ins {
+            let current = vm.active_coroutines().next_back();
+            while self.frames.len() > 1 && self.frames.last().is_some_and(|frame| {
+                frame.coroutine == current && (vm.frame_ptr() == 0 || frame.frame_ptr < vm.frame_ptr())
+            }) {
+                self.frames.pop();
+                events.push(FrameEvent::Exit);
+            }
+        }
+        events
+    }
+
+    // A tail call ends the caller's frame before entering the callee's.
+    pub fn on_call(&mut self, vm: &Interpreter, to: usize) -> Vec<FrameEvent> {
+        let mut events = Vec::new();
+        if let Some(Instruction::TailCall) = self.last_ins {
+            events.extend(self.exit(vm));
+        }
+        events.push(self.enter(vm, to));
+        events
+    }
+
+    pub fn on_return(&mut self, vm: &Interpreter) -> Vec<FrameEvent> {
+        self.exit(vm).into_iter().collect()
+    }
+
+    fn enter(&mut self, vm: &Interpreter, entry: usize) -> FrameEvent {
+        let coroutine = vm.active_coroutines().next_back();
+        self.frames.push(TrackedFrame { frame_ptr: vm.frame_ptr(), coroutine });
+        FrameEvent::Enter(entry)
+    }
+
+    // Returns from frames that ended when their coroutine was left are ignored.
+    fn exit(&mut self, vm: &Interpreter) -> Option<FrameEvent> {
+        let current = vm.active_coroutines().next_back();
+        if self.frames.len() > 1 && self.frames.last().is_some_and(|frame| frame.coroutine == current) {
+            self.frames.pop();
+            return Some(FrameEvent::Exit);
+        }
+        None
+    }
+
+    fn leave_coroutines(&mut self, vm: &Interpreter) -> Vec<FrameEvent> {
+        let mut events = Vec::new();
+        while self.frames.len() > 1 && self.frames.last().is_some_and(|frame| {
+            frame.coroutine.is_some_and(|coroutine| !vm.active_coroutines().any(|active| active == coroutine))
+        }) {
+            self.frames.pop();
+            events.push(FrameEvent::Exit);
+        }
+        events
+    }
+}
+
+// Prints one line per instruction with its operands and the top of the stack
+// afterwards, plus a line for every call, return, host call and trap.
+pub struct Tracer<W: Write + Send + 'static> {
+    out: W,
+    line_open: bool,
+}
+
+impl Tracer<Stderr> {
+    pub fn stderr() -> Tracer<Stderr> {
+        Tracer::new(std::io::stderr())
+    }
+}
+
+impl<W: Write + Send + 'static> Tracer<W> {
+    pub fn new(out: W) -> Tracer<W> {
+        Tracer { out, line_open: false }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+// Write errors are ignored, tracing should never change how a script runs.
+impl<W: Write + Send + 'static> ExecutionHook for Tracer<W> {
+    fn before_instruction(&mut self, vm: &Interpreter, pc: usize, ins: Instruction) {
+        self.line_open = true;
+        let _ = write!(self.out, "{} : {:?}", pc, ins);
+        for (i, val) in operand_values(vm.program().code(), pc).iter().enumerate() {
+            let _ = write!(self.out, "{}{}", if i == 0 { " " } else { ", " }, val);
+        }
+    }
+
+    fn after_instruction(&mut self, vm: &Interpreter, _pc: usize, _ins: Instruction) {
+        self.line_open = false;
+        let _ = match vm.stack_depth() {
+            0 => writeln!(self.out, " ; depth 0"),
+            depth => writeln!(self.out, " ; depth {}, top {}", depth, vm.peek_value()),
+        };
+    }
+
+    fn on_call(&mut self, vm: &Interpreter, pc: usize, to: usize) {
+        let _ = writeln!(self.out, "  call {} -> {}, fp {}", pc, to, vm.frame_ptr());
+    }
+
+    fn on_return(&mut self, vm: &Interpreter, pc: usize, to: usize) {
+        let _ = writeln!(self.out, "  return {} -> {}, fp {}", pc, to, vm.frame_ptr());
+    }
+
+    fn on_host_call(&mut self, _vm: &Interpreter, _pc: usize, call: CompilerCall) {
+        let _ = writeln!(self.out, "  host call {:?}", call);
+    }
+
+    fn on_trap(&mut self, _vm: &Interpreter, trap: &Trap) {
+        if self.line_open {
+            self.line_open = false;
+            let _ = writeln!(self.out);
+        }
+        let _ = write!(self.out, "{}", trap);
+    }
+}
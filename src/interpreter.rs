@@ -19,21 +19,98 @@
  CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
  */
 
-use std::{panic, ops::{Index, IndexMut}, fmt::Display};
+use std::{any::Any, cell::RefCell, fmt::Display, sync::Arc};
+
+use crate::heap::{Heap, is_heap_address};
+use crate::error::{Backtrace, BacktraceFrame, Trap, VmError};
+use crate::gc::{Channel, Object, ObjectHeap, Value};
+use crate::module::{Constant, Function, Handler, Module};
+use crate::debug_info::DebugInfo;
+use crate::hook::ExecutionHook;
+use crate::program::Program;
+use crate::replay::{Event, ExecutionLog, ReplayError};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
 
 pub struct InstructionList {
     pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    pub functions: Vec<Function>,
+    pub debug_info: DebugInfo,
+    pub handlers: Vec<Handler>,
+}
+
+impl Default for InstructionList {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl InstructionList {
     pub fn new() -> InstructionList {
-        InstructionList { code: Vec::new() }
+        InstructionList {
+            code: Vec::new(),
+            constants: Vec::new(),
+            functions: Vec::new(),
+            debug_info: DebugInfo::default(),
+            handlers: Vec::new(),
+        }
+    }
+
+    pub fn into_module(self) -> Module {
+        Module {
+            code: self.code,
+            constants: self.constants,
+            functions: self.functions,
+            debug_info: self.debug_info,
+            handlers: self.handlers,
+        }
+    }
+
+    pub fn add_handler(&mut self, start: usize, end: usize, target: usize, depth: u32) {
+        self.handlers.push(Handler { start: start as u32, end: end as u32, target: target as u32, depth });
+    }
+
+    // Everything emitted from here on is attributed to this source position.
+    pub fn set_location(&mut self, file: &str, line: u32, column: u32) {
+        self.debug_info.add_location(self.code.len() as u32, file, line, column);
+    }
+
+    // Marks the current offset as the entry of a named function and returns its index.
+    pub fn add_function(&mut self, name: &str) -> u32 {
+        self.functions.push(Function { name: name.to_string(), offset: self.code.len() as u32 });
+        self.functions.len() as u32 - 1
+    }
+
+    pub fn add_constant(&mut self, constant: Constant) -> u32 {
+        match self.constants.iter().position(|c| *c == constant) {
+            Some(index) => index as u32,
+            None => {
+                self.constants.push(constant);
+                self.constants.len() as u32 - 1
+            }
+        }
+    }
+
+    pub fn add_string_constant(&mut self, str: &str) -> u32 {
+        self.add_constant(Constant::String(str.to_string()))
+    }
+
+    pub fn add_bytes_constant(&mut self, bytes: &[u8]) -> u32 {
+        self.add_constant(Constant::Bytes(bytes.to_vec()))
+    }
+
+    pub fn add_i64_constant(&mut self, val: i64) -> u32 {
+        self.add_constant(Constant::I64(val))
     }
 
     pub fn len(&self) -> usize {
         self.code.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
     pub fn push_instruction(&mut self, ins: Instruction) {
         self.code.push(u8::from(ins))
     }
@@ -93,12 +170,16 @@ impl InstructionList {
 pub enum CompilerCall {
     None,
     PrintInt,
+    PrintStr,
+    Format,
 }
 
 impl From<&str> for CompilerCall {
     fn from(call: &str) -> Self {
         match call.trim() {
             "print_int" => CompilerCall::PrintInt,
+            "print_str" => CompilerCall::PrintStr,
+            "format" => CompilerCall::Format,
             _ => CompilerCall::None,
         }
     }
@@ -108,6 +189,8 @@ impl From<u8> for CompilerCall {
     fn from(call: u8) -> Self {
         match call {
             1 => Self::PrintInt,
+            2 => Self::PrintStr,
+            3 => Self::Format,
             _ => Self::None,
         }
     }
@@ -121,6 +204,7 @@ impl From<CompilerCall> for u8 {
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Flags {
     pub not_zero: bool,
     pub less_then: bool,
@@ -133,7 +217,42 @@ pub struct Flags {
     pub div_by_zero: bool,
 }
 
+impl Default for Flags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Flags {
+    fn encode(&self, out: &mut Writer) {
+        for (_, set) in self.fields() {
+            out.bool(set);
+        }
+    }
+
+    fn decode(input: &mut Reader) -> Result<Flags, SnapshotError> {
+        let mut bits = [false; 9];
+        for bit in &mut bits {
+            *bit = input.bool()?;
+        }
+        let [not_zero, less_then, larger_then, equals, overflow, underflow, halted, carry, div_by_zero] = bits;
+        Ok(Flags { not_zero, less_then, larger_then, equals, overflow, underflow, halted, carry, div_by_zero })
+    }
+
+    // Every flag by name, in bit order: `not_zero` is bit 0 through `div_by_zero` in bit 8.
+    pub fn fields(&self) -> [(&'static str, bool); 9] {
+        [
+            ("not_zero", self.not_zero), ("less_then", self.less_then), ("larger_then", self.larger_then),
+            ("equals", self.equals), ("overflow", self.overflow), ("underflow", self.underflow),
+            ("halted", self.halted), ("carry", self.carry), ("div_by_zero", self.div_by_zero),
+        ]
+    }
+
+    // One bit per flag, laid out as `fields` lists them.
+    pub fn to_bits(&self) -> u32 {
+        self.fields().iter().enumerate().fold(0, |acc, (i, (_, set))| acc | (*set as u32) << i)
+    }
+
     pub fn new() -> Flags {
         Flags {
         not_zero: false,
@@ -149,8 +268,28 @@ impl Flags {
     }
 }
 
+// Frame layout. The stack grows downwards and `frame_ptr` points at the first
+// free slot after `Call` has pushed its bookkeeping, so for a function taking
+// `n` arguments pushed in order a0..an-1 and reserving `m` locals with `Enter m`:
+//
+//   frame_ptr + 2 + n   a0
+//   ...
+//   frame_ptr + 3       an-1
+//   frame_ptr + 2       return address
+//   frame_ptr + 1       caller frame_ptr
+//   frame_ptr - 0       local 0
+//   ...
+//   frame_ptr - (m-1)   local m-1
+//
+// `Leave` drops the locals and anything pushed after them and `RetN n` returns
+// while also popping the n arguments. Results are passed back through a slot the
+// caller reserves before pushing the arguments, at `frame_ptr + 3 + n`.
+//
+// `CallClosure` passes the closure itself as an extra last argument, so inside a
+// closure it sits at `frame_ptr + 3`, the declared arguments start at
+// `frame_ptr + 4` and the function returns with `RetN n+1`.
 #[repr(u8)]
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     Nop,
     Hlt,
@@ -184,6 +323,44 @@ pub enum Instruction {
     LesserEqual,
     Equal,
     NotEqual,
+    Alloc,
+    Free,
+    Realloc,
+    Load8,
+    Load16,
+    Load32,
+    Store8,
+    Store16,
+    Store32,
+    NewArray,
+    NewRecord,
+    GetField,
+    SetField,
+    GetElem,
+    SetElem,
+    ArrayLen,
+    Gc,
+    PushConst,
+    Enter,
+    Leave,
+    RetN,
+    TailCall,
+    CallIndirect,
+    MakeClosure,
+    LoadUpvalue,
+    CallClosure,
+    Throw,
+    CoCreate,
+    Resume,
+    Yield,
+    CoStatus,
+    Spawn,
+    Join,
+    Sleep,
+    NewChannel,
+    Send,
+    Recv,
+    TryRecv,
 }
 
 impl From<u8> for Instruction {
@@ -221,11 +398,84 @@ impl From<u8> for Instruction {
             29 => Self::LesserEqual,
             30 => Self::Equal,
             31 => Self::NotEqual,
+            32 => Self::Alloc,
+            33 => Self::Free,
+            34 => Self::Realloc,
+            35 => Self::Load8,
+            36 => Self::Load16,
+            37 => Self::Load32,
+            38 => Self::Store8,
+            39 => Self::Store16,
+            40 => Self::Store32,
+            41 => Self::NewArray,
+            42 => Self::NewRecord,
+            43 => Self::GetField,
+            44 => Self::SetField,
+            45 => Self::GetElem,
+            46 => Self::SetElem,
+            47 => Self::ArrayLen,
+            48 => Self::Gc,
+            49 => Self::PushConst,
+            50 => Self::Enter,
+            51 => Self::Leave,
+            52 => Self::RetN,
+            53 => Self::TailCall,
+            54 => Self::CallIndirect,
+            55 => Self::MakeClosure,
+            56 => Self::LoadUpvalue,
+            57 => Self::CallClosure,
+            58 => Self::Throw,
+            59 => Self::CoCreate,
+            60 => Self::Resume,
+            61 => Self::Yield,
+            62 => Self::CoStatus,
+            63 => Self::Spawn,
+            64 => Self::Join,
+            65 => Self::Sleep,
+            66 => Self::NewChannel,
+            67 => Self::Send,
+            68 => Self::Recv,
+            69 => Self::TryRecv,
             _ => Self::Nop,
         }
     }
 }
 
+impl Instruction {
+    // Like `From<u8>`, but rejects bytes past the last op code instead of reading them as `Nop`.
+    pub fn decode(val: u8) -> Option<Instruction> {
+        if val <= Instruction::TryRecv as u8 {
+            Some(Instruction::from(val))
+        } else {
+            None
+        }
+    }
+
+    // Byte widths of the operands following the op code, in encoding order.
+    pub fn operands(self) -> &'static [usize] {
+        match self {
+            Instruction::PushReg | Instruction::PopReg => &[1],
+            Instruction::StackAdd | Instruction::Call |
+            Instruction::Push | Instruction::CompilerCall |
+            Instruction::StoreRelative | Instruction::LoadRelative |
+            Instruction::DerefAssign | Instruction::DerefAssignRelative |
+            Instruction::Lea | Instruction::Store |
+            Instruction::Load | Instruction::Jmp |
+            Instruction::Jz | Instruction::Jnz |
+            Instruction::NewRecord | Instruction::GetField |
+            Instruction::SetField | Instruction::PushConst |
+            Instruction::Enter | Instruction::RetN |
+            Instruction::MakeClosure | Instruction::LoadUpvalue => &[4],
+            Instruction::TailCall => &[4, 1, 1],
+            _ => &[],
+        }
+    }
+
+    pub fn operand_len(self) -> usize {
+        self.operands().iter().sum()
+    }
+}
+
 impl From<Instruction> for u8 {
     fn from(ins: Instruction) -> Self {
         unsafe {
@@ -234,481 +484,1661 @@ impl From<Instruction> for u8 {
     }
 }
 
-struct Stack {
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Stack {
     stack: Vec<i32>,
+    refs: Vec<bool>,
     ptr: usize,
+    writes: Option<Vec<SlotWrite>>,
+    // Reads happen through `&self`, so their log needs interior mutability.
+    reads: RefCell<Option<Vec<usize>>>,
+}
+
+// A stack slot changing from `old` to `new`, as logged while tracking writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SlotWrite {
+    pub slot: usize,
+    pub old: Value,
+    pub new: Value,
+}
+
+// Every stack slot an instruction read or wrote, in the order it touched them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StackAccess {
+    pub reads: Vec<usize>,
+    pub writes: Vec<SlotWrite>,
 }
 
 impl Stack {
     fn new(size: usize) -> Stack {
-        Stack { stack: vec![0; size], ptr: size-1 }
+        Stack { stack: vec![0; size], refs: vec![false; size], ptr: size-1, writes: None, reads: RefCell::new(None) }
     }
 
-    fn get(&self, index: usize) -> i32 {
-        self.stack[index]
+    fn get(&self, index: usize) -> Result<i32, VmError> {
+        match self.stack.get(index) {
+            Some(val) => {
+                if let Some(reads) = self.reads.borrow_mut().as_mut() {
+                    reads.push(index);
+                }
+                Ok(*val)
+            }
+            None => Err(VmError::StackOutOfBounds(index as u32 as i32)),
+        }
     }
 
-    fn set(&mut self, index: usize, val: i32) {
-        self.stack[index] = val;
+    fn get_value(&self, index: usize) -> Result<Value, VmError> {
+        let val = self.get(index)?;
+        Ok(self.tag(index, val))
     }
 
-    fn push(&mut self, val: i32) {
-        if self.ptr >= self.stack.len() {
-            panic!("Tried to push beyond stack limit! : {} / {}", self.ptr, self.ptr as u32 as i32);
-        } else {
-            self.stack[self.ptr] = val;
-            self.ptr -= 1;
+    // Reads a slot without logging it, for the host looking at the stack.
+    fn peek(&self, index: usize) -> Result<Value, VmError> {
+        match self.stack.get(index) {
+            Some(val) => Ok(self.tag(index, *val)),
+            None => Err(VmError::StackOutOfBounds(index as u32 as i32)),
         }
     }
 
-    fn pop(&mut self) -> i32 {
-        self.ptr += 1;
-        self.stack[self.ptr]
+    fn tag(&self, index: usize, val: i32) -> Value {
+        if self.refs[index] {
+            Value::Ref(val as u32)
+        } else {
+            Value::Int(val)
+        }
     }
-}
-
-fn debug(str: &str) {
-    //print!("{}", str);
-}
 
-pub struct Instructions {
-    instructions: Vec<u8>,
-}
+    fn set_value(&mut self, index: usize, val: Value) -> Result<(), VmError> {
+        if index >= self.stack.len() {
+            return Err(VmError::StackOutOfBounds(index as u32 as i32));
+        }
+        if self.writes.is_some() {
+            let old = self.peek(index)?;
+            self.log_write(index, old, val);
+        }
+        self.stack[index] = val.raw();
+        self.refs[index] = matches!(val, Value::Ref(_));
+        Ok(())
+    }
 
-impl Instructions {
-    fn push(&mut self, val: u8) {
-        self.instructions.push(val);
+    fn push(&mut self, val: i32) -> Result<(), VmError> {
+        self.push_value(Value::Int(val))
     }
 
-    fn len(&self) -> usize {
-        self.instructions.len()
+    fn push_value(&mut self, val: Value) -> Result<(), VmError> {
+        if self.ptr >= self.stack.len() {
+            Err(VmError::StackOverflow)
+        } else {
+            self.set_value(self.ptr, val)?;
+            self.ptr = self.ptr.wrapping_sub(1);
+            Ok(())
+        }
     }
 
-    pub fn push_instruction(&mut self, ins: Instruction) {
-        self.instructions.push(u8::from(ins))
+    fn pop(&mut self) -> Result<i32, VmError> {
+        Ok(self.pop_value()?.raw())
     }
 
-    pub fn push_u8_operand(&mut self, val: u8) {
-        self.instructions.push(val)
+    fn pop_value(&mut self) -> Result<Value, VmError> {
+        let ptr = self.ptr.wrapping_add(1);
+        if ptr >= self.stack.len() {
+            return Err(VmError::StackUnderflow);
+        }
+        self.ptr = ptr;
+        let val = self.get_value(ptr)?;
+        if let Value::Ref(index) = val {
+            self.log_write(ptr, val, Value::Int(index as i32));
+        }
+        self.refs[ptr] = false;
+        Ok(val)
     }
 
-    pub fn push_u16_operand(&mut self, val: u16) {
-        self.push_u8_operand(val as u8);
-        self.push_u8_operand((val >> 8) as u8);
+    fn log_write(&mut self, slot: usize, old: Value, new: Value) {
+        if let Some(writes) = &mut self.writes {
+            writes.push(SlotWrite { slot, old, new });
+        }
     }
 
-    pub fn push_u32_operand(&mut self, val: u32) {
-        self.push_u16_operand(val as u16);
-        self.push_u16_operand((val >> 16) as u16);
+    fn depth(&self) -> usize {
+        (self.stack.len() - 1).wrapping_sub(self.ptr)
     }
 
-    pub fn push_i32_operand(&mut self, val: i32) {
-        self.push_u32_operand(bytemuck::cast(val))
+    fn encode(&self, out: &mut Writer) {
+        out.usize(self.stack.len());
+        out.usize(self.ptr);
+        for (val, is_ref) in self.stack.iter().zip(&self.refs) {
+            out.i32(*val);
+            out.bool(*is_ref);
+        }
     }
 
-    pub fn set_u8_operand(&mut self, val: u8, index: usize) {
-        self.instructions[index] = val;
+    // Frame addresses are relative to the top, so a stack of any other size can not be resumed.
+    fn decode(input: &mut Reader, size: usize) -> Result<Stack, SnapshotError> {
+        let len = input.len(5)?;
+        let ptr = input.usize()?;
+        if len != size {
+            return Err(SnapshotError::Corrupt("stack has the wrong size"));
+        }
+        if ptr >= len && ptr != usize::MAX {
+            return Err(SnapshotError::Corrupt("stack pointer out of bounds"));
+        }
+        let mut stack = Stack { stack: Vec::with_capacity(len), refs: Vec::with_capacity(len), ptr, writes: None, reads: RefCell::new(None) };
+        for _ in 0..len {
+            stack.stack.push(input.i32()?);
+            stack.refs.push(input.bool()?);
+        }
+        Ok(stack)
     }
 
-    pub fn set_u16_operand(&mut self, val: u16, index: usize) {
-        self.set_u8_operand(val as u8, index);
-        self.set_u8_operand((val >> 8u32) as u8, index+1);
+    pub(crate) fn roots(&self) -> impl Iterator<Item = u32> + '_ {
+        self.stack.iter().zip(&self.refs).filter(|(_, is_ref)| **is_ref).map(|(val, _)| *val as u32)
     }
+}
 
-    pub fn set_u32_operand(&mut self, val: u32, index: usize) {
-        self.set_u16_operand(val as u16, index);
-        self.set_u16_operand((val >> 16u32) as u16, index+2);
+// Decodes the operands of the instruction at `index`, stopping at the end of the code.
+pub fn operand_values(code: &[u8], index: usize) -> Vec<i32> {
+    let Some(op) = code.get(index) else {
+        return Vec::new();
+    };
+    let mut values = Vec::new();
+    let mut operand = index + 1;
+    for width in Instruction::from(*op).operands() {
+        let Some(bytes) = code.get(operand..operand + width) else {
+            break;
+        };
+        values.push(match bytes {
+            [a, b, c, d] => i32::from_le_bytes([*a, *b, *c, *d]),
+            [a] => *a as i32,
+            _ => 0,
+        });
+        operand += width;
     }
+    values
+}
 
-    pub fn set_i32_operand(&mut self, val: i32, index: usize) {
-        self.set_u32_operand(bytemuck::cast(val), index);
+pub struct Disassembly<'a> {
+    pub code: &'a [u8],
+    pub debug_info: Option<&'a DebugInfo>,
+}
+
+impl Display for Disassembly<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut index = 0;
+        let mut last_location = None;
+        while index < self.code.len() {
+            if let Some(location) = self.debug_info.and_then(|debug_info| debug_info.location(index)) {
+                if last_location.as_ref() != Some(&location) {
+                    writeln!(f, "; {}", location)?;
+                    last_location = Some(location);
+                }
+            }
+
+            let ins = Instruction::from(self.code[index]);
+            write!(f, "{} : {:?}", index, ins)?;
+            for (i, val) in operand_values(self.code, index).iter().enumerate() {
+                write!(f, "{}{}", if i == 0 { " " } else { ", " }, val)?;
+            }
+            writeln!(f)?;
+
+            index += 1 + ins.operand_len();
+        }
+        Ok(())
     }
+}
 
-    pub fn get_u8(&self, index: usize) -> u8 {
-        self.instructions[index]
+pub(crate) const STACK_SIZE: usize = 1024;
+const COROUTINE_STACK_SIZE: usize = 256;
+const COROUTINE_RETURN: u32 = u32::MAX;
+// Longest array `NewArray` allocates, the length comes off the stack at runtime.
+const MAX_ARRAY_LEN: usize = 1 << 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    Suspended,
+    Running,
+    Dead,
+}
+
+// A suspended coroutine owns its own stack and registers. While it runs these are
+// swapped with the interpreter's, so the object then holds the resumer's state.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coroutine {
+    stack: Stack,
+    ptr: usize,
+    frame_ptr: usize,
+    flags: Flags,
+    nested: Vec<ActiveCoroutine>,
+    pub status: CoroutineStatus,
+}
+
+impl Coroutine {
+    pub(crate) fn encode(&self, out: &mut Writer) {
+        self.stack.encode(out);
+        out.usize(self.ptr);
+        out.usize(self.frame_ptr);
+        self.flags.encode(out);
+        encode_active(out, &self.nested);
+        out.u8(self.status as u8);
     }
 
-    pub fn get_u16(&self, index: usize) -> u16 {
-        self.get_u8(index) as u16 + ((self.get_u8(index + 1) as u16) << 8)
+    pub(crate) fn decode(input: &mut Reader) -> Result<Coroutine, SnapshotError> {
+        Ok(Coroutine {
+            stack: Stack::decode(input, COROUTINE_STACK_SIZE)?,
+            ptr: input.usize()?,
+            frame_ptr: input.usize()?,
+            flags: Flags::decode(input)?,
+            nested: decode_active(input)?,
+            status: match input.u8()? {
+                0 => CoroutineStatus::Suspended,
+                1 => CoroutineStatus::Running,
+                2 => CoroutineStatus::Dead,
+                _ => return Err(SnapshotError::Corrupt("invalid coroutine status")),
+            },
+        })
     }
 
-    pub fn get_u32(&self, index: usize) -> u32 {
-        self.get_u16(index) as u32 + ((self.get_u16(index + 2) as u32) << 16)
+    pub(crate) fn roots(&self) -> impl Iterator<Item = u32> + '_ {
+        self.stack.roots().chain(self.nested.iter().map(|active| active.object.raw() as u32))
     }
+}
 
-    pub fn get_i32(&self, index: usize) -> i32 {
-        bytemuck::cast(self.get_u32(index))
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CoroutineResult {
+    Yielded(Value),
+    Finished(Value),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct ActiveCoroutine {
+    object: Value,
+    host: bool,
+}
+
+fn encode_active(out: &mut Writer, active: &[ActiveCoroutine]) {
+    out.usize(active.len());
+    for active in active {
+        out.value(active.object);
+        out.bool(active.host);
     }
 }
 
-impl Display for Instructions {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut index = 0;
-        while index < self.len() {
-            let ins = Instruction::from(self.instructions[index]);
-            write!(f, "{} : {:?}", index, ins)?;
+fn decode_active(input: &mut Reader) -> Result<Vec<ActiveCoroutine>, SnapshotError> {
+    let len = input.len(6)?;
+    (0..len).map(|_| Ok(ActiveCoroutine { object: input.value()?, host: input.bool()? })).collect()
+}
 
-            match ins {
-                Instruction::StackAdd | Instruction::PushReg |
-                Instruction::Call | Instruction::Push |
-                Instruction::CompilerCall | Instruction::StoreRelative |
-                Instruction::LoadRelative | Instruction::DerefAssign |
-                Instruction::DerefAssignRelative | Instruction::Lea |
-                Instruction::PopReg | Instruction::Store |
-                Instruction::Load | Instruction::Jmp |
-                Instruction::Jz | Instruction::Jnz => {
-                    write!(f, " {}", self.get_i32(index+1))?;
-                    index += 4;
-                }
-                _ => {}
-            }
+// Instructions that need a `Scheduler` hand control back to it with one of these.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaskRequest {
+    Spawn(i32),
+    Join(i32),
+    Sleep(i32),
+    Recv(Value),
+}
 
-            writeln!(f, "")?;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TaskExit {
+    Yielded(Value),
+    Finished(Value),
+    Request(TaskRequest),
+    OutOfFuel,
+}
 
-            index += 1;
-        }
-        Ok(())
-    }
+enum Journal {
+    Record(ExecutionLog),
+    Replay(ExecutionLog, usize),
 }
 
-impl Index<usize> for Instructions {
-    type Output = u8;
+enum Status {
+    Running,
+    Halted,
+    Returned(CoroutineResult),
+    Request(TaskRequest),
+}
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.instructions[index]
+// Without a scheduler a blocked `Recv` is left to be retried once the host has sent
+// something, every other request is an error.
+fn request_error(request: TaskRequest) -> VmError {
+    match request {
+        TaskRequest::Recv(channel) => VmError::WouldBlock(channel.raw() as u32),
+        _ => VmError::NoScheduler,
     }
 }
 
-impl IndexMut<usize> for Instructions {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.instructions[index]
+fn type_mismatch(expected: &'static str, found: &Object) -> VmError {
+    VmError::TypeMismatch { expected, found: format!("{:?}", found) }
+}
+
+fn access_width(ins: Instruction) -> usize {
+    match ins {
+        Instruction::Load8 | Instruction::Store8 => 1,
+        Instruction::Load16 | Instruction::Store16 => 2,
+        _ => 4,
     }
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registers {
+    pub ptr: usize,
+    pub ins_ptr: usize,
+    pub stack_ptr: usize,
+    pub frame_ptr: usize,
+    pub flags: Flags,
+}
+
 pub struct Interpreter {
     stack: Stack,
-    pub instructions: Instructions,
+    heap: Heap,
+    objects: ObjectHeap,
+    program: Arc<Program>,
+    coroutines: Vec<ActiveCoroutine>,
+    pinned: Vec<Value>,
     ptr: usize,
+    ins_ptr: usize,
     frame_ptr: usize,
     flags: Flags,
+    fuel: Option<u64>,
+    hook: Option<Box<dyn ExecutionHook>>,
+    journal: Option<Journal>,
 }
 
 impl Interpreter {
+    // Runs the code whether or not it verifies, faults trap when they are reached.
+    // Build a `Program` first to reject bad code up front.
     pub fn new(instructions: Vec<u8>) -> Interpreter {
+        Self::from_module(Module::new(instructions))
+    }
+
+    pub fn from_module(module: Module) -> Interpreter {
+        Self::with_program(Arc::new(Program::unverified(module)))
+    }
+
+    pub fn with_program(program: Arc<Program>) -> Interpreter {
         Interpreter {
-            stack: Stack::new(1024),
-            instructions: Instructions { instructions },
+            stack: Stack::new(STACK_SIZE),
+            heap: Heap::new(),
+            objects: ObjectHeap::new(),
+            program,
+            coroutines: Vec::new(),
+            pinned: Vec::new(),
             ptr: 0,
+            ins_ptr: 0,
             frame_ptr: 0,
             flags: Flags::new(),
+            fuel: None,
+            hook: None,
+            journal: None,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), Trap> {
+        self.execute().map_err(|error| self.trap(error))
+    }
+
+    // Executes a single instruction, ignoring the fuel limit. Returns whether the
+    // program has halted.
+    pub fn single_step(&mut self) -> Result<bool, Trap> {
+        let status = self.step();
+        self.step_result(status)
+    }
+
+    // Like `single_step`, also returning every slot of the current stack the
+    // instruction read or wrote. Hooks looking at the stack are not counted.
+    pub(crate) fn single_step_tracked(&mut self) -> (Result<bool, Trap>, StackAccess) {
+        self.stack.writes = Some(Vec::new());
+        *self.stack.reads.get_mut() = Some(Vec::new());
+        let status = self.step();
+        let access = StackAccess {
+            reads: self.stack.reads.get_mut().take().unwrap_or_default(),
+            writes: self.stack.writes.take().unwrap_or_default(),
+        };
+        (self.step_result(status), access)
+    }
+
+    fn step_result(&mut self, status: Result<Status, VmError>) -> Result<bool, Trap> {
+        match status {
+            Ok(Status::Halted) => Ok(true),
+            Ok(Status::Request(request)) => Err(self.trap(request_error(request))),
+            Ok(_) => Ok(false),
+            Err(error) => Err(self.trap(error)),
+        }
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers { ptr: self.ptr, ins_ptr: self.ins_ptr, stack_ptr: self.stack.ptr, frame_ptr: self.frame_ptr, flags: self.flags.clone() }
+    }
+
+    pub(crate) fn set_registers(&mut self, registers: Registers) {
+        self.ptr = registers.ptr;
+        self.ins_ptr = registers.ins_ptr;
+        self.stack.ptr = registers.stack_ptr;
+        self.frame_ptr = registers.frame_ptr;
+        self.flags = registers.flags;
+    }
+
+    // Puts the slots back to how they were before `writes`, newest write first.
+    pub(crate) fn undo_writes(&mut self, writes: &[SlotWrite]) -> Result<(), VmError> {
+        for write in writes.iter().rev() {
+            self.stack.set_value(write.slot, write.old)?;
         }
+        Ok(())
+    }
+
+    pub fn stack_value(&self, slot: usize) -> Result<Value, VmError> {
+        self.stack.peek(slot)
     }
 
-    pub fn run(&mut self) {
+    pub fn set_stack_value(&mut self, slot: usize, val: Value) -> Result<(), VmError> {
+        self.stack.set_value(slot, val)
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.heap
+    }
+
+    // Replaces any hook already attached. Hooks are not part of a snapshot.
+    pub fn set_hook(&mut self, hook: impl ExecutionHook) {
+        self.hook = Some(Box::new(hook));
+    }
+
+    pub fn hook<H: ExecutionHook>(&self) -> Option<&H> {
+        self.hook.as_deref().and_then(|hook| (hook as &dyn Any).downcast_ref())
+    }
+
+    pub fn hook_mut<H: ExecutionHook>(&mut self) -> Option<&mut H> {
+        self.hook.as_deref_mut().and_then(|hook| (hook as &mut dyn Any).downcast_mut())
+    }
+
+    // Detaches the hook if it is an `H`, leaving any other hook in place.
+    pub fn take_hook<H: ExecutionHook>(&mut self) -> Option<H> {
+        self.hook::<H>()?;
+        let hook: Box<dyn Any> = self.hook.take()?;
+        hook.downcast().ok().map(|hook| *hook)
+    }
+
+    // Starts logging every input that reaches the run from outside, see `Event`.
+    pub fn record(&mut self) {
+        self.journal = Some(Journal::Record(ExecutionLog::new(self.program.hash())));
+    }
+
+    // From here on inputs come from `log` instead of the host and channels, and
+    // the run traps with `Diverged` as soon as it no longer follows the log.
+    pub fn replay(&mut self, log: ExecutionLog) -> Result<(), ReplayError> {
+        if log.program_hash() != self.program.hash() {
+            return Err(ReplayError::ProgramMismatch { expected: self.program.hash(), found: log.program_hash() });
+        }
+        self.journal = Some(Journal::Replay(log, 0));
+        Ok(())
+    }
+
+    // Whether a replay has used up its log.
+    pub fn replay_finished(&self) -> bool {
+        match &self.journal {
+            Some(Journal::Replay(log, position)) => *position >= log.events().len(),
+            _ => true,
+        }
+    }
+
+    // Stops recording or replaying and hands back the log.
+    pub fn take_log(&mut self) -> Option<ExecutionLog> {
+        match self.journal.take()? {
+            Journal::Record(log) | Journal::Replay(log, _) => Some(log),
+        }
+    }
+
+    // Events recorded or replayed so far.
+    pub(crate) fn journal_position(&self) -> usize {
+        match &self.journal {
+            Some(Journal::Record(log)) => log.events().len(),
+            Some(Journal::Replay(_, position)) => *position,
+            None => 0,
+        }
+    }
+
+    // Runs steps that already ran once, from a restored snapshot taken at journal
+    // `position`. The hook is detached so they are not observed twice and they take
+    // their inputs from the journal again, a recording drops what comes after them.
+    pub(crate) fn rerun<R>(&mut self, position: usize, rerun: impl FnOnce(&mut Interpreter) -> R) -> R {
+        let hook = self.hook.take();
+        let recording = matches!(self.journal, Some(Journal::Record(_)));
+        self.journal = self.journal.take().map(|(Journal::Record(log) | Journal::Replay(log, _))| Journal::Replay(log, position));
+        let result = rerun(self);
+        self.journal = self.journal.take().map(|journal| match journal {
+            Journal::Replay(mut log, position) if recording => {
+                log.truncate(position);
+                Journal::Record(log)
+            }
+            journal => journal,
+        });
+        self.hook = hook;
+        result
+    }
+
+    fn replaying(&self) -> bool {
+        matches!(self.journal, Some(Journal::Replay(..)))
+    }
+
+    // Returns `event` as it happened, or its logged counterpart when replaying.
+    pub(crate) fn journal(&mut self, event: Event) -> Result<Event, VmError> {
+        match &mut self.journal {
+            None => Ok(event),
+            Some(Journal::Record(log)) => {
+                log.push(event.clone());
+                Ok(event)
+            }
+            Some(Journal::Replay(log, position)) => match log.events().get(*position) {
+                Some(logged) if event.same_site(logged) => {
+                    *position += 1;
+                    Ok(logged.clone())
+                }
+                logged => Err(VmError::Diverged {
+                    event: *position,
+                    expected: logged.map_or("nothing more".to_string(), |logged| logged.to_string()),
+                    found: event.to_string(),
+                }),
+            },
+        }
+    }
+
+    pub(crate) fn host_value(&mut self, val: Value) -> Result<Value, VmError> {
+        match self.journal(Event::HostValue(val))? {
+            Event::HostValue(logged) => Ok(logged),
+            _ => Ok(val),
+        }
+    }
+
+    // A replay still takes the logged value out of the channel, so values sent
+    // from inside the VM drain exactly as they did while recording.
+    fn receive(&mut self, channel: Value) -> Result<Option<Value>, VmError> {
+        let channel_handle = self.channel(channel)?;
+        let pc = self.ins_ptr as u32;
+        if self.replaying() {
+            let Event::Receive { value, .. } = self.journal(Event::Receive { pc, value: None })? else {
+                return Ok(None);
+            };
+            if value.is_some() {
+                channel_handle.try_recv();
+            }
+            return Ok(value);
+        }
+        let value = channel_handle.try_recv();
+        self.journal(Event::Receive { pc, value })?;
+        Ok(value)
+    }
+
+    pub(crate) fn channel_ready(&mut self, channel: Value) -> Result<bool, VmError> {
+        let ready = !self.replaying() && self.channel(channel).is_ok_and(|channel| !channel.is_empty());
+        match self.journal(Event::ChannelReady { channel, ready })? {
+            Event::ChannelReady { ready, .. } => Ok(ready),
+            _ => Ok(ready),
+        }
+    }
+
+    fn trap(&mut self, error: VmError) -> Trap {
+        let trap = Trap { error, backtrace: self.backtrace() };
+        if let Some(mut hook) = self.hook.take() {
+            hook.on_trap(self, &trap);
+            self.hook = Some(hook);
+        }
+        trap
+    }
+
+    // The innermost frame is the faulting instruction, every other frame is the
+    // return address `Call` saved for it.
+    pub fn backtrace(&self) -> Backtrace {
+        self.backtrace_from(self.ins_ptr)
+    }
+
+    // Like `backtrace`, but starting at the next instruction to run instead of the
+    // last one run, as a debugger stopped between instructions shows it.
+    pub fn call_stack(&self) -> Backtrace {
+        self.backtrace_from(self.ptr)
+    }
+
+    fn backtrace_from(&self, pc: usize) -> Backtrace {
+        let mut frames = vec![self.backtrace_frame(pc, pc, self.frame_ptr)];
+        let mut frame_ptr = self.frame_ptr;
+        while frame_ptr != 0 && frames.len() <= self.stack.stack.len() {
+            let (Ok(saved), Ok(pc)) = (self.stack.peek(frame_ptr + 1), self.stack.peek(frame_ptr + 2)) else {
+                break;
+            };
+            let pc = pc.raw() as u32 as usize;
+            frame_ptr = saved.raw() as u32 as usize;
+            frames.push(self.backtrace_frame(pc, pc.saturating_sub(1), frame_ptr));
+        }
+        Backtrace { frames }
+    }
+
+    // Return addresses point past the call, so callers look up `pc - 1` instead.
+    fn backtrace_frame(&self, pc: usize, lookup: usize, frame_ptr: usize) -> BacktraceFrame {
+        BacktraceFrame {
+            pc,
+            frame_ptr,
+            function: self.function_name(lookup),
+            location: self.program.debug_info().location(lookup),
+        }
+    }
+
+    pub fn program(&self) -> &Arc<Program> {
+        &self.program
+    }
+
+    // Swaps in new code under the current state. Every offset and index the state
+    // holds has to mean the same in `program`, so its code, constants and functions
+    // must start with the current ones. Hands `program` back when they do not.
+    pub fn set_program(&mut self, program: Arc<Program>) -> Result<(), Arc<Program>> {
+        let old = &self.program;
+        if !program.code().starts_with(old.code()) || !program.constants().starts_with(old.constants())
+            || !program.functions().starts_with(old.functions()) {
+            return Err(program);
+        }
+        self.program = program;
+        Ok(())
+    }
+
+    pub fn disassembly(&self) -> Disassembly<'_> {
+        self.program.disassembly()
+    }
+
+    pub fn function_name(&self, pc: usize) -> Option<String> {
+        self.program.functions().iter()
+            .filter(|function| function.offset as usize <= pc)
+            .max_by_key(|function| function.offset)
+            .map(|function| function.name.clone())
+    }
+
+    // Limits how many more instructions `run` executes before trapping with
+    // `OutOfFuel`. The trap leaves the interpreter where it stopped, so topping the
+    // fuel up and calling `run` again carries on. `None` means no limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    // Captures everything needed to carry on later, possibly in another process,
+    // with `restore`. Host-side channel handles are not part of it.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.program.hash(), |out| {
+            self.stack.encode(out);
+            out.usize(self.ptr);
+            out.usize(self.ins_ptr);
+            out.usize(self.frame_ptr);
+            self.flags.encode(out);
+            self.heap.encode(out);
+            self.objects.encode(out);
+            encode_active(out, &self.coroutines);
+            out.values(self.pinned.iter());
+            match self.fuel {
+                Some(fuel) => {
+                    out.bool(true);
+                    out.u64(fuel);
+                }
+                None => out.bool(false),
+            }
+        })
+    }
+
+    pub fn restore(program: Arc<Program>, snapshot: &Snapshot) -> Result<Interpreter, SnapshotError> {
+        if snapshot.program_hash() != program.hash() {
+            return Err(SnapshotError::ProgramMismatch { expected: program.hash(), found: snapshot.program_hash() });
+        }
+        let mut interpreter = Interpreter::with_program(program);
+        interpreter.load_snapshot(snapshot)?;
+        Ok(interpreter)
+    }
+
+    // Replaces the state in place, keeping the hook and any record or replay.
+    pub(crate) fn load_snapshot(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let mut input = snapshot.reader()?;
+        let stack = Stack::decode(&mut input, STACK_SIZE)?;
+        let (ptr, ins_ptr, frame_ptr) = (input.usize()?, input.usize()?, input.usize()?);
+        let flags = Flags::decode(&mut input)?;
+        let heap = Heap::decode(&mut input)?;
+        let objects = ObjectHeap::decode(&mut input)?;
+        let coroutines = decode_active(&mut input)?;
+        let pinned = input.values()?;
+        let fuel = if input.bool()? { Some(input.u64()?) } else { None };
+        if !input.is_empty() {
+            return Err(SnapshotError::Corrupt("trailing bytes"));
+        }
+        *self = Interpreter {
+            stack, heap, objects, coroutines, pinned, ptr, ins_ptr, frame_ptr, flags, fuel,
+            program: self.program.clone(),
+            hook: self.hook.take(),
+            journal: self.journal.take(),
+        };
+        Ok(())
+    }
+
+    fn execute(&mut self) -> Result<(), VmError> {
         loop {
-            let ins = self.next_instruction();
-            debug(&format!("{:?} ", ins));
-            match ins {
-                Instruction::Nop => {},
-                Instruction::Hlt => return,
-                Instruction::Lea => {
-                    let location = self.next_i32() + self.frame_ptr as u32 as i32;
-                    self.stack_push(location);
-                    debug(&format!("{}\n", location));
-                },
-                Instruction::I32Add => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = a + b;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
-                },
-                Instruction::I32Sub => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = a - b;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
-                },
-                Instruction::I32Mul => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = a * b;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
-                },
-                Instruction::I32Div => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = a / b;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
-                },
-                Instruction::Push => {
-                    let val = self.next_i32();
-                    self.stack_push(val);
-                    debug(&format!("{}\n", val));
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return Err(VmError::OutOfFuel);
                 }
-                Instruction::Pop => {
-                    let val = self.stack_pop();
-                    debug(&format!("{}\n", val));
+                *fuel -= 1;
+            }
+            match self.step()? {
+                Status::Halted => return Ok(()),
+                Status::Request(request) => return Err(request_error(request)),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn create_coroutine(&mut self, function: u32) -> Result<Value, VmError> {
+        self.maybe_collect_garbage();
+        let destination = self.function_offset(function as i32)?;
+        let mut stack = Stack::new(COROUTINE_STACK_SIZE);
+        // Returning from the coroutine function lands past the end of the code,
+        // which reads as `Hlt` and finishes the coroutine. The address is fixed so it
+        // stays past the end when code is appended with `set_program`.
+        stack.push(0)?;
+        stack.push(COROUTINE_RETURN as i32)?;
+        stack.push(0)?;
+        let coroutine = Coroutine {
+            frame_ptr: stack.ptr,
+            stack,
+            ptr: destination,
+            flags: Flags::new(),
+            nested: Vec::new(),
+            status: CoroutineStatus::Suspended,
+        };
+        Ok(self.objects.alloc(Object::Coroutine(Box::new(coroutine))))
+    }
+
+    // The channel object is pinned so it outlives any script dropping it; hand the
+    // returned value to the script with `push_value` or as a task argument.
+    pub fn create_channel(&mut self) -> (Value, Channel) {
+        let channel = Channel::new();
+        let val = self.objects.alloc(Object::Channel(channel.clone()));
+        self.pin(val);
+        (val, channel)
+    }
+
+    pub fn push_value(&mut self, val: Value) -> Result<(), VmError> {
+        let val = self.host_value(val)?;
+        self.stack.push_value(val)
+    }
+
+    pub fn channel(&self, channel: Value) -> Result<Channel, VmError> {
+        match self.objects.get(channel)? {
+            Object::Channel(channel) => Ok(channel.clone()),
+            obj => Err(type_mismatch("a channel", obj)),
+        }
+    }
+
+    // The coroutines the run is inside of, outermost first. The last one is running.
+    pub fn active_coroutines(&self) -> impl DoubleEndedIterator<Item = Value> + '_ {
+        self.coroutines.iter().map(|active| active.object)
+    }
+
+    pub fn coroutine_status(&self, coroutine: Value) -> Result<CoroutineStatus, VmError> {
+        match self.objects.get(coroutine)? {
+            Object::Coroutine(coroutine) => Ok(coroutine.status),
+            obj => Err(type_mismatch("a coroutine", obj)),
+        }
+    }
+
+    // Runs the coroutine until it yields or finishes, handing `val` to it as the
+    // result of its pending `Yield` (or as the top of its stack on the first resume).
+    pub fn resume(&mut self, coroutine: Value, val: Value) -> Result<CoroutineResult, Trap> {
+        let result = self.host_value(val).and_then(|val| self.enter_coroutine(coroutine, Some(val), true)).and_then(|_| loop {
+            match self.step()? {
+                Status::Returned(result) => return Ok(result),
+                Status::Request(request) => return Err(request_error(request)),
+                _ => {}
+            }
+        });
+        result.map_err(|error| self.abandon_coroutine(error))
+    }
+
+    // Like `resume`, but gives up after `fuel` instructions or when the coroutine asks
+    // the scheduler for something, leaving it suspended exactly where it stopped.
+    pub fn resume_task(&mut self, task: Value, val: Option<Value>, fuel: usize) -> Result<TaskExit, Trap> {
+        let result = self.enter_coroutine(task, val, true).and_then(|_| {
+            for _ in 0..fuel {
+                match self.step()? {
+                    Status::Running | Status::Halted => {}
+                    Status::Returned(CoroutineResult::Yielded(val)) => return Ok(TaskExit::Yielded(val)),
+                    Status::Returned(CoroutineResult::Finished(val)) => return Ok(TaskExit::Finished(val)),
+                    Status::Request(request) => {
+                        self.suspend_coroutine()?;
+                        return Ok(TaskExit::Request(request));
+                    }
                 }
+            }
+            self.suspend_coroutine()?;
+            Ok(TaskExit::OutOfFuel)
+        });
+        result.map_err(|error| self.abandon_coroutine(error))
+    }
+
+    fn enter_coroutine(&mut self, object: Value, val: Option<Value>, host: bool) -> Result<(), VmError> {
+        if self.coroutine_status(object)? != CoroutineStatus::Suspended {
+            return Err(VmError::CoroutineNotSuspended(object.raw() as u32));
+        }
+        let nested = self.switch_coroutine(object, CoroutineStatus::Running)?;
+        self.coroutines.push(ActiveCoroutine { object, host });
+        self.coroutines.extend(nested);
+        match val {
+            Some(val) => self.stack.push_value(val),
+            None => Ok(()),
+        }
+    }
+
+    // Parks the coroutine the host resumed together with everything it resumed in turn.
+    fn suspend_coroutine(&mut self) -> Result<(), VmError> {
+        let index = self.coroutines.iter().rposition(|active| active.host).ok_or(VmError::NotInCoroutine)?;
+        let nested = self.coroutines.split_off(index + 1);
+        let active = self.coroutines.pop().ok_or(VmError::NotInCoroutine)?;
+        self.switch_coroutine(active.object, CoroutineStatus::Suspended)?;
+        if let Object::Coroutine(coroutine) = self.objects.get_mut(active.object)? {
+            coroutine.nested = nested;
+        }
+        Ok(())
+    }
+
+    // A trap kills the coroutine, but the host gets its own registers back.
+    fn abandon_coroutine(&mut self, error: VmError) -> Trap {
+        let trap = self.trap(error);
+        if let Some(index) = self.coroutines.iter().rposition(|active| active.host) {
+            self.coroutines.truncate(index + 1);
+            if let Some(active) = self.coroutines.pop() {
+                let _ = self.switch_coroutine(active.object, CoroutineStatus::Dead);
+            }
+        }
+        trap
+    }
+
+    fn leave_coroutine(&mut self, result: CoroutineResult) -> Result<Status, VmError> {
+        let active = self.coroutines.pop().ok_or(VmError::NotInCoroutine)?;
+        let (status, val) = match result {
+            CoroutineResult::Yielded(val) => (CoroutineStatus::Suspended, val),
+            CoroutineResult::Finished(val) => (CoroutineStatus::Dead, val),
+        };
+        self.switch_coroutine(active.object, status)?;
+        if active.host {
+            Ok(Status::Returned(result))
+        } else {
+            self.stack.push_value(val)?;
+            Ok(Status::Running)
+        }
+    }
+
+    fn switch_coroutine(&mut self, object: Value, status: CoroutineStatus) -> Result<Vec<ActiveCoroutine>, VmError> {
+        let coroutine = match self.objects.get_mut(object)? {
+            Object::Coroutine(coroutine) => coroutine,
+            obj => return Err(type_mismatch("a coroutine", obj)),
+        };
+        std::mem::swap(&mut self.stack, &mut coroutine.stack);
+        coroutine.stack.writes = None;
+        *coroutine.stack.reads.get_mut() = None;
+        std::mem::swap(&mut self.ptr, &mut coroutine.ptr);
+        std::mem::swap(&mut self.frame_ptr, &mut coroutine.frame_ptr);
+        std::mem::swap(&mut self.flags, &mut coroutine.flags);
+        coroutine.status = status;
+        Ok(std::mem::take(&mut coroutine.nested))
+    }
+
+    // `Hlt` inside a coroutine finishes that coroutine with the value on top of its stack.
+    fn halt(&mut self) -> Result<Status, VmError> {
+        if self.coroutines.is_empty() {
+            return Ok(Status::Halted);
+        }
+        let val = self.stack.pop_value().unwrap_or(Value::Int(0));
+        self.leave_coroutine(CoroutineResult::Finished(val))
+    }
+
+    // Without a hook this is a single branch on top of `step_instruction`.
+    fn step(&mut self) -> Result<Status, VmError> {
+        let Some(mut hook) = self.hook.take() else {
+            return self.step_instruction();
+        };
+
+        let pc = self.ptr;
+        let ins = self.program.code().get(pc).map_or(Instruction::Hlt, |op| Instruction::from(*op));
+        hook.before_instruction(self, pc, ins);
+        let result = self.step_instruction();
+        if result.is_ok() {
+            hook.after_instruction(self, pc, ins);
+            match ins {
+                Instruction::Call | Instruction::CallIndirect |
+                Instruction::CallClosure | Instruction::TailCall => hook.on_call(self, pc, self.ptr),
+                Instruction::Ret | Instruction::RetN => hook.on_return(self, pc, self.ptr),
                 Instruction::CompilerCall => {
-                    let function = self.next_i32();
-                    debug(&format!("{}\n", function));
-                    match function {
-                        0 => {},
-                        1 => {
-                            println!("Outputed: {}", self.stack.get(self.stack.ptr+1))
-                        },
-                        _ => panic!("Compiler call failed function with index does not exist : [{}]", function)
-                    }
+                    let call = operand_values(self.program.code(), pc).first().map_or(0, |call| *call as u8);
+                    hook.on_host_call(self, pc, CompilerCall::from(call));
                 }
-                Instruction::Call => {
-                    let destination = self.next_i32();
-                    self.stack_push(self.ptr as u32 as i32);
-                    self.stack_push(self.frame_ptr as u32 as i32);
-                    self.ptr = destination as u32 as usize;
-                    self.frame_ptr = self.stack.ptr;
-                    debug(&format!("{}, {}\n", destination, self.frame_ptr));
+                _ => {}
+            }
+        }
+        self.hook = Some(hook);
+        result
+    }
+
+    fn step_instruction(&mut self) -> Result<Status, VmError> {
+        self.ins_ptr = self.ptr;
+        let ins = self.next_instruction()?;
+        match ins {
+            Instruction::Nop => {},
+            Instruction::Hlt => return self.halt(),
+            Instruction::Lea => {
+                let location = self.next_i32()?.wrapping_add(self.frame_ptr as u32 as i32);
+                self.stack_push(location)?;
+            },
+            Instruction::I32Add => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = a.wrapping_add(b);
+                self.stack_push(c)?;
+            },
+            Instruction::I32Sub => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = a.wrapping_sub(b);
+                self.stack_push(c)?;
+            },
+            Instruction::I32Mul => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = a.wrapping_mul(b);
+                self.stack_push(c)?;
+            },
+            Instruction::I32Div => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                if b == 0 {
+                    return Err(VmError::DivideByZero);
                 }
-                Instruction::Ret => {
-                    let frame_ptr = self.stack_pop();
-                    self.frame_ptr = frame_ptr as u32 as usize;
-                    let destination = self.stack_pop();
-                    self.ptr = destination as u32 as usize;
-                    debug(&format!("{}, {}\n", destination, self.frame_ptr));
+                let c = a.wrapping_div(b);
+                self.stack_push(c)?;
+            },
+            Instruction::Push => {
+                let val = self.next_i32()?;
+                self.stack_push(val)?;
+            }
+            Instruction::Pop => {
+                self.stack_pop()?;
+            }
+            Instruction::CompilerCall => {
+                let function = self.next_i32()?;
+                self.journal(Event::HostCall { pc: self.ins_ptr as u32, call: function })?;
+                match function {
+                    0 => {},
+                    1 => {
+                        println!("Outputed: {}", self.stack.get(self.stack.ptr+1)?)
+                    },
+                    2 => {
+                        match self.objects.get(self.stack.get_value(self.stack.ptr+1)?)? {
+                            Object::String(str) => println!("{}", str),
+                            obj => return Err(type_mismatch("a string", obj)),
+                        }
+                    },
+                    3 => self.format()?,
+                    _ => return Err(VmError::InvalidCompilerCall(function)),
                 }
-                Instruction::PopReg => {
-                    let dst = self.next_u8();
-                    let val = self.stack_pop();
-                    debug(&format!("DST: {}, VAL: {}\n", dst, val));
-                    match dst {
-                        0 => {},
-                        1 => self.ptr = val as u32 as usize,
-                        2 => self.stack.ptr = val as u32 as usize,
-                        3 => self.frame_ptr = val as u32 as usize,
-                        _ => panic!(),
-                    }
+            }
+            Instruction::Call => {
+                let destination = self.next_i32()?;
+                self.call(destination as u32 as usize)?;
+            }
+            Instruction::CallIndirect => {
+                let function = self.stack_pop()?;
+                let destination = self.function_offset(function)?;
+                self.call(destination)?;
+            }
+            Instruction::MakeClosure => {
+                self.maybe_collect_garbage();
+                let count = self.next_i32()?;
+                let function = self.stack_pop()?;
+                self.function_offset(function)?;
+                let upvalues = self.pop_values(count)?;
+                self.alloc_object(Object::Closure { function: function as u32, upvalues })?;
+            }
+            Instruction::LoadUpvalue => {
+                let index = self.next_i32()?;
+                let closure = self.stack.get_value(self.frame_ptr + 3)?;
+                let val = match self.objects.get(closure)? {
+                    Object::Closure { upvalues, .. } => upvalues.get(index as u32 as usize).copied(),
+                    obj => return Err(type_mismatch("a closure", obj)),
+                };
+                match val {
+                    Some(val) => self.stack.push_value(val)?,
+                    None => return Err(VmError::IndexOutOfBounds { object: closure.raw() as u32, index }),
+                }
+            }
+            Instruction::CallClosure => {
+                let closure = self.stack.pop_value()?;
+                let function = match self.objects.get(closure)? {
+                    Object::Closure { function, .. } => *function as i32,
+                    obj => return Err(type_mismatch("a closure", obj)),
+                };
+                let destination = self.function_offset(function)?;
+                self.stack.push_value(closure)?;
+                self.call(destination)?;
+            }
+            Instruction::Ret => {
+                let frame_ptr = self.stack_pop()?;
+                self.frame_ptr = frame_ptr as u32 as usize;
+                let destination = self.stack_pop()?;
+                self.ptr = destination as u32 as usize;
+            }
+            Instruction::Enter => {
+                let locals = self.next_i32()?;
+                for _ in 0..locals {
+                    self.stack_push(0)?;
+                }
+            }
+            Instruction::Leave => {
+                while self.stack.ptr < self.frame_ptr {
+                    self.stack_pop()?;
                 }
-                Instruction::PushReg => {
-                    let src = self.next_u8();
-                    debug(&format!("{}\n", src));
-                    match src {
-                        0 => {},
-                        1 => self.stack_push(self.ptr as u32 as i32),
-                        2 => self.stack_push(self.stack.ptr as u32 as i32),
-                        3 => self.stack_push(self.frame_ptr as u32 as i32),
-                        _ => panic!(),
+            }
+            Instruction::RetN => {
+                let args = self.next_i32()?;
+                let frame_ptr = self.stack_pop()?;
+                self.frame_ptr = frame_ptr as u32 as usize;
+                let destination = self.stack_pop()?;
+                self.ptr = destination as u32 as usize;
+                for _ in 0..args {
+                    self.stack_pop()?;
+                }
+            }
+            Instruction::TailCall => {
+                let destination = self.next_i32()?;
+                let args = self.next_u8()? as usize;
+                let params = self.next_u8()? as usize;
+                self.tail_call(args, params)?;
+                self.ptr = destination as u32 as usize;
+            }
+            Instruction::Throw => {
+                let val = self.stack.pop_value()?;
+                self.throw(val)?;
+            }
+            Instruction::CoCreate => {
+                let function = self.stack_pop()?;
+                let coroutine = self.create_coroutine(function as u32)?;
+                self.stack.push_value(coroutine)?;
+            }
+            Instruction::Resume => {
+                let coroutine = self.stack.pop_value()?;
+                let val = self.stack.pop_value()?;
+                self.enter_coroutine(coroutine, Some(val), false)?;
+            }
+            Instruction::Yield => {
+                let val = self.stack.pop_value()?;
+                return self.leave_coroutine(CoroutineResult::Yielded(val));
+            }
+            Instruction::CoStatus => {
+                let coroutine = self.stack.pop_value()?;
+                let status = self.coroutine_status(coroutine)?;
+                self.stack_push(status as i32)?;
+            }
+            Instruction::Spawn => {
+                let function = self.stack_pop()?;
+                return Ok(Status::Request(TaskRequest::Spawn(function)));
+            }
+            Instruction::Join => {
+                let task = self.stack_pop()?;
+                return Ok(Status::Request(TaskRequest::Join(task)));
+            }
+            Instruction::Sleep => {
+                let ticks = self.stack_pop()?;
+                return Ok(Status::Request(TaskRequest::Sleep(ticks)));
+            }
+            Instruction::NewChannel => {
+                self.maybe_collect_garbage();
+                self.alloc_object(Object::Channel(Channel::new()))?;
+            }
+            Instruction::Send => {
+                let channel = self.stack.pop_value()?;
+                let val = self.stack.pop_value()?;
+                self.channel(channel)?.lock().push_back(val);
+            }
+            Instruction::Recv => {
+                let channel = self.stack.pop_value()?;
+                let val = self.receive(channel)?;
+                match val {
+                    Some(val) => self.stack.push_value(val)?,
+                    None => {
+                        // Leave the instruction to run again once the channel has data.
+                        self.stack.push_value(channel)?;
+                        self.ptr = self.ins_ptr;
+                        return Ok(Status::Request(TaskRequest::Recv(channel)));
                     }
-                },
-                Instruction::Load => {
-                    let location = self.next_i32();
-                    let val = self.stack.get(location as u32 as usize);
-                    self.stack_push(val);
-                    debug(&format!("&{}:${}\n", location, val));
-                },
-                Instruction::Store => {
-                    let location = self.next_i32();
-                    let val = self.stack_pop();
-                    self.stack.set(location as u32 as usize, val);
-                    debug(&format!("&{}:${}\n", location, val));
                 }
-                Instruction::LoadRelative => {
-                    let location = self.frame_ptr as u32 as i32 + self.next_i32();
-                    let val = self.stack.get(location as u32 as usize);
-                    self.stack_push(val);
-                    debug(&format!("&{}:${}\n", location - self.frame_ptr as u32 as i32, val));
-                },
-                Instruction::StoreRelative => {
-                    let location = self.frame_ptr as u32 as i32 + self.next_i32();
-                    let val = self.stack_pop();
-                    self.stack.set(location as u32 as usize, val);
-                    debug(&format!("&{}:${}\n", location - self.frame_ptr as u32 as i32, val));
+            }
+            Instruction::TryRecv => {
+                let channel = self.stack.pop_value()?;
+                let val = self.receive(channel)?;
+                self.stack.push_value(val.unwrap_or(Value::Int(0)))?;
+                self.stack_push(val.is_some() as i32)?;
+            }
+            Instruction::PopReg => {
+                let dst = self.next_u8()?;
+                let val = self.stack_pop()?;
+                match dst {
+                    0 => {},
+                    1 => self.ptr = val as u32 as usize,
+                    2 => self.stack.ptr = val as u32 as usize,
+                    3 => self.frame_ptr = val as u32 as usize,
+                    _ => return Err(VmError::InvalidRegister(dst)),
                 }
-                Instruction::StackAdd => {
-                    let offset = self.next_i32();
-                    self.stack.ptr = (self.stack.ptr as u32 as i32 + offset) as u32 as usize;
-                    debug(&format!("{}\n", offset));
+            }
+            Instruction::PushReg => {
+                let src = self.next_u8()?;
+                match src {
+                    0 => {},
+                    1 => self.stack_push(self.ptr as u32 as i32)?,
+                    2 => self.stack_push(self.stack.ptr as u32 as i32)?,
+                    3 => self.stack_push(self.frame_ptr as u32 as i32)?,
+                    _ => return Err(VmError::InvalidRegister(src)),
                 }
-                Instruction::DerefAssignRelative => {
-                    let ptr = self.frame_ptr as u32 as i32 + self.next_i32();
-                    let location = self.stack.get(ptr as u32 as usize);
-                    let val = self.stack_pop();
-                    self.stack.set(location as u32 as usize, val);
-                    debug(&format!("&{}:${}\n", location - self.frame_ptr as u32 as i32, val));
+            },
+            Instruction::Load => {
+                let location = self.next_i32()?;
+                let val = self.stack.get_value(location as u32 as usize)?;
+                self.stack.push_value(val)?;
+            },
+            Instruction::Store => {
+                let location = self.next_i32()?;
+                let val = self.stack.pop_value()?;
+                self.stack.set_value(location as u32 as usize, val)?;
+            }
+            Instruction::LoadRelative => {
+                let location = (self.frame_ptr as u32 as i32).wrapping_add(self.next_i32()?);
+                let val = self.stack.get_value(location as u32 as usize)?;
+                self.stack.push_value(val)?;
+            },
+            Instruction::StoreRelative => {
+                let location = (self.frame_ptr as u32 as i32).wrapping_add(self.next_i32()?);
+                let val = self.stack.pop_value()?;
+                self.stack.set_value(location as u32 as usize, val)?;
+            }
+            Instruction::StackAdd => {
+                let offset = self.next_i32()?;
+                self.stack.ptr = (self.stack.ptr as u32 as i32).wrapping_add(offset) as u32 as usize;
+            }
+            Instruction::DerefAssignRelative => {
+                let ptr = (self.frame_ptr as u32 as i32).wrapping_add(self.next_i32()?);
+                let location = self.stack.get(ptr as u32 as usize)?;
+                let val = self.stack.pop_value()?;
+                self.store_address(location, val)?;
+            }
+            Instruction::DerefAssign => {
+                let ptr = self.next_i32()?;
+                let location = self.stack.get(ptr as u32 as usize)?;
+                let val = self.stack.pop_value()?;
+                self.store_address(location, val)?;
+            }
+            Instruction::Deref => {
+                let ptr = self.stack_pop()?;
+                let val = self.load_address(ptr)?;
+                self.stack.push_value(val)?;
+            }
+            Instruction::Cmp => {
+                let lhs = self.stack_pop()?;
+                let rhs = self.stack_pop()?;
+                let diff = lhs as i64 - rhs as i64;
+                if diff < 0 {
+                    self.flags.less_then = true;
+                    self.flags.larger_then = false;
+                    self.flags.not_zero = true;
+                    self.flags.equals = false;
+                } else if diff > 0 {
+                    self.flags.less_then = false;
+                    self.flags.larger_then = true;
+                    self.flags.not_zero = true;
+                    self.flags.equals = false;
                 }
-                Instruction::DerefAssign => {
-                    let ptr = self.next_i32();
-                    let location = self.stack.get(ptr as u32 as usize);
-                    let val = self.stack_pop();
-                    self.stack.set(location as u32 as usize, val);
-                    debug(&format!("&{}:${}\n", location, val));
+                else {
+                    self.flags.less_then = false;
+                    self.flags.larger_then = false;
+                    self.flags.not_zero = false;
+                    self.flags.equals = true;
                 }
-                Instruction::Deref => {
-                    let ptr = self.stack_pop();
-                    let val = self.stack.get(ptr as u32 as usize);
-                    self.stack_push(val);
-                    debug(&format!("&{}:${}\n", ptr, val));
+            },
+            Instruction::Jmp => {
+                let dst = self.next_i32()?;
+                self.ptr = dst as u32 as usize;
+            }
+            Instruction::Jz => {
+                let dst = self.next_i32()?;
+                let val = self.stack_pop()?;
+                if val == 0 {
+                    self.ptr = dst as u32 as usize;
                 }
-                Instruction::Cmp => {
-                    let lhs = self.stack_pop();
-                    let rhs = self.stack_pop();
-                    let diff = lhs - rhs;
-                    if diff < 0 {
-                        self.flags.less_then = true;
-                        self.flags.larger_then = false;
-                        self.flags.not_zero = true;
-                        self.flags.equals = false;
-                    } else if diff > 0 {
-                        self.flags.less_then = false;
-                        self.flags.larger_then = true;
-                        self.flags.not_zero = true;
-                        self.flags.equals = false;
-                    }
-                    else {
-                        self.flags.less_then = false;
-                        self.flags.larger_then = false;
-                        self.flags.not_zero = false;
-                        self.flags.equals = true;
-                    }
-                },
-                Instruction::Jmp => {
-                    let dst = self.next_i32();
+            }
+            Instruction::Jnz => {
+                let dst = self.next_i32()?;
+                let val = self.stack_pop()?;
+                if val != 0 {
                     self.ptr = dst as u32 as usize;
                 }
-                Instruction::Jz => {
-                    let dst = self.next_i32();
-                    let val = self.stack_pop();
-                    if val == 0 {
-                        self.ptr = dst as u32 as usize;
-                    }
+            }
+            Instruction::Greater => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = (a > b) as i32;
+                self.stack_push(c)?;
+            }
+            Instruction::GreaterEqual => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = (a >= b) as i32;
+                self.stack_push(c)?;
+            }
+            Instruction::Lesser => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = (a < b) as i32;
+                self.stack_push(c)?;
+            }
+            Instruction::LesserEqual => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = (a <= b) as i32;
+                self.stack_push(c)?;
+            }
+            Instruction::Equal => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = (a == b) as i32;
+                self.stack_push(c)?;
+            }
+            Instruction::NotEqual => {
+                let a = self.stack_pop()?;
+                let b = self.stack_pop()?;
+                let c = (a != b) as i32;
+                self.stack_push(c)?;
+            }
+            Instruction::Alloc => {
+                let size = self.stack_pop()?;
+                let address = self.heap.alloc(size as u32 as usize)?;
+                self.stack_push(address)?;
+            }
+            Instruction::Free => {
+                let address = self.stack_pop()?;
+                self.heap.free(address)?;
+            }
+            Instruction::Realloc => {
+                let address = self.stack_pop()?;
+                let size = self.stack_pop()?;
+                let new_address = self.heap.realloc(address, size as u32 as usize)?;
+                self.stack_push(new_address)?;
+            }
+            Instruction::Load8 | Instruction::Load16 | Instruction::Load32 => {
+                let address = self.stack_pop()?;
+                let val = self.heap.load(address, access_width(ins))? as i32;
+                self.stack_push(val)?;
+            }
+            Instruction::Store8 | Instruction::Store16 | Instruction::Store32 => {
+                let address = self.stack_pop()?;
+                let val = self.stack_pop()?;
+                self.heap.store(address, access_width(ins), val as u32)?;
+            }
+            Instruction::NewArray => {
+                self.maybe_collect_garbage();
+                let len = self.stack_pop()?;
+                if len < 0 {
+                    return Err(VmError::NegativeLength(len));
                 }
-                Instruction::Greater => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = (a > b) as i32;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
+                if len as usize > MAX_ARRAY_LEN {
+                    return Err(VmError::HeapExhausted(len as usize * std::mem::size_of::<Value>()));
                 }
-                Instruction::GreaterEqual => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = (a >= b) as i32;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
+                self.alloc_object(Object::Array(vec![Value::Int(0); len as usize]))?;
+            }
+            Instruction::NewRecord => {
+                self.maybe_collect_garbage();
+                let count = self.next_i32()?;
+                let fields = self.pop_values(count)?;
+                self.alloc_object(Object::Record(fields))?;
+            }
+            Instruction::GetField => {
+                let field = self.next_i32()?;
+                let record = self.stack.pop_value()?;
+                let val = match self.objects.get(record)? {
+                    Object::Record(fields) => fields.get(field as u32 as usize).copied(),
+                    obj => return Err(type_mismatch("a record", obj)),
+                };
+                match val {
+                    Some(val) => self.stack.push_value(val)?,
+                    None => return Err(VmError::IndexOutOfBounds { object: record.raw() as u32, index: field }),
                 }
-                Instruction::Lesser => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = (a < b) as i32;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
+            }
+            Instruction::SetField => {
+                let field = self.next_i32()?;
+                let record = self.stack.pop_value()?;
+                let val = self.stack.pop_value()?;
+                let slot = match self.objects.get_mut(record)? {
+                    Object::Record(fields) => fields.get_mut(field as u32 as usize),
+                    obj => return Err(type_mismatch("a record", obj)),
+                };
+                match slot {
+                    Some(slot) => *slot = val,
+                    None => return Err(VmError::IndexOutOfBounds { object: record.raw() as u32, index: field }),
                 }
-                Instruction::LesserEqual => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = (a <= b) as i32;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
+            }
+            Instruction::GetElem => {
+                let array = self.stack.pop_value()?;
+                let index = self.stack_pop()?;
+                let val = match self.objects.get(array)? {
+                    Object::Array(elems) => elems.get(index as u32 as usize).copied(),
+                    Object::String(str) => str.as_bytes().get(index as u32 as usize).map(|byte| Value::Int(*byte as i32)),
+                    Object::Bytes(bytes) => bytes.get(index as u32 as usize).map(|byte| Value::Int(*byte as i32)),
+                    obj => return Err(type_mismatch("an array", obj)),
+                };
+                match val {
+                    Some(val) => self.stack.push_value(val)?,
+                    None => return Err(VmError::IndexOutOfBounds { object: array.raw() as u32, index }),
                 }
-                Instruction::Equal => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = (a == b) as i32;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
+            }
+            Instruction::SetElem => {
+                let array = self.stack.pop_value()?;
+                let index = self.stack_pop()?;
+                let val = self.stack.pop_value()?;
+                let in_bounds = match self.objects.get_mut(array)? {
+                    Object::Array(elems) => elems.get_mut(index as u32 as usize).map(|slot| *slot = val).is_some(),
+                    Object::Bytes(bytes) => bytes.get_mut(index as u32 as usize).map(|byte| *byte = val.raw() as u8).is_some(),
+                    obj => return Err(type_mismatch("an array", obj)),
+                };
+                if !in_bounds {
+                    return Err(VmError::IndexOutOfBounds { object: array.raw() as u32, index });
                 }
-                Instruction::NotEqual => {
-                    let a = self.stack_pop();
-                    let b = self.stack_pop();
-                    let c = (a != b) as i32;
-                    self.stack_push(c);
-                    debug(&format!("{}, {}\n", a, b));
+            }
+            Instruction::ArrayLen => {
+                let obj = self.stack.pop_value()?;
+                let len = self.objects.get(obj)?.len();
+                self.stack_push(len as i32)?;
+            }
+            Instruction::PushConst => {
+                let index = self.next_i32()?;
+                match self.program.constants().get(index as u32 as usize) {
+                    Some(Constant::String(str)) => {
+                        let object = Object::String(str.clone());
+                        self.maybe_collect_garbage();
+                        self.alloc_object(object)?;
+                    }
+                    Some(Constant::Bytes(bytes)) => {
+                        let object = Object::Bytes(bytes.clone());
+                        self.maybe_collect_garbage();
+                        self.alloc_object(object)?;
+                    }
+                    Some(Constant::I64(val)) => {
+                        let val = *val;
+                        self.stack_push(val as i32)?;
+                        self.stack_push((val >> 32) as i32)?;
+                    }
+                    None => return Err(VmError::InvalidConstant(index)),
                 }
-                ins => panic!("Invalid instruction with op code of : {}", u8::from(ins)),
+            }
+            Instruction::Gc => {
+                self.collect_garbage();
             }
         }
+        Ok(Status::Running)
     }
 
-    pub fn push_u8_operand(&mut self, val: u8) {
-        self.instructions.push(val)
+    pub fn peek(&self) -> i32 {
+        self.peek_value().raw()
     }
 
-    pub fn push_u16_operand(&mut self, val: u16) {
-        self.push_u8_operand(val as u8);
-        self.push_u8_operand((val >> 8) as u8);
+    // The offset of the next instruction to execute.
+    pub fn pc(&self) -> usize {
+        self.ptr
     }
 
-    pub fn push_u32_operand(&mut self, val: u32) {
-        self.push_u16_operand(val as u16);
-        self.push_u16_operand((val >> 16) as u16);
+    pub fn frame_ptr(&self) -> usize {
+        self.frame_ptr
     }
 
-    pub fn push_i32_operand(&mut self, val: i32) {
-        self.push_u32_operand(bytemuck::cast(val))
+    pub fn flags(&self) -> &Flags {
+        &self.flags
     }
 
-    pub fn set_u8_operand(&mut self, val: u8, index: usize) {
-        self.instructions[index] = val;
+    pub fn stack_depth(&self) -> usize {
+        self.stack.depth()
     }
 
-    pub fn set_u16_operand(&mut self, val: u16, index: usize) {
-        self.set_u8_operand(val as u8, index);
-        self.set_u8_operand((val >> 8u32) as u8, index+1);
-    }
-
-    pub fn set_u32_operand(&mut self, val: u32, index: usize) {
-        self.set_u16_operand(val as u16, index);
-        self.set_u16_operand((val >> 16u32) as u16, index+2);
+    pub fn peek_value(&self) -> Value {
+        self.stack.peek(self.stack.ptr.wrapping_add(1)).unwrap_or(Value::Int(0))
     }
 
-    pub fn set_i32_operand(&mut self, val: i32, index: usize) {
-        //println!("i32_op:{}", val);
-        self.set_u32_operand(bytemuck::cast(val), index);
+    pub fn live_objects(&self) -> usize {
+        self.objects.live()
     }
 
-    fn get_u8(&self, index: usize) -> u8 {
-        self.instructions[index]
+    fn get_u8(&self, index: usize) -> Result<u8, VmError> {
+        match self.program.code().get(index) {
+            Some(val) => Ok(*val),
+            None => Err(VmError::CodeOutOfBounds(index)),
+        }
     }
 
-    fn get_u16(&self, index: usize) -> u16 {
-        self.get_u8(index) as u16 + ((self.get_u8(index + 1) as u16) << 8)
+    fn get_u16(&self, index: usize) -> Result<u16, VmError> {
+        Ok(self.get_u8(index)? as u16 + ((self.get_u8(index + 1)? as u16) << 8))
     }
 
-    fn get_u32(&self, index: usize) -> u32 {
-        self.get_u16(index) as u32 + ((self.get_u16(index + 2) as u32) << 16)
+    fn get_u32(&self, index: usize) -> Result<u32, VmError> {
+        Ok(self.get_u16(index)? as u32 + ((self.get_u16(index + 2)? as u32) << 16))
     }
 
-    fn next_instruction(&mut self) -> Instruction {
-        if self.ptr >= self.instructions.len() {
-            return Instruction::Hlt;
+    fn next_instruction(&mut self) -> Result<Instruction, VmError> {
+        if self.ptr >= self.program.len() {
+            return Ok(Instruction::Hlt);
         }
-        let ins = Instruction::from(self.get_u8(self.ptr));
+        let op = self.get_u8(self.ptr)?;
+        let ins = Instruction::decode(op).ok_or(VmError::InvalidInstruction(op))?;
         self.ptr += 1;
-        ins
+        Ok(ins)
     }
 
-    fn next_i32(&mut self) -> i32 {
-        let val = bytemuck::cast(self.get_u32(self.ptr));
+    fn next_i32(&mut self) -> Result<i32, VmError> {
+        let val = bytemuck::cast(self.get_u32(self.ptr)?);
         self.ptr += 4;
-        val
+        Ok(val)
     }
 
-    fn next_u8(&mut self) -> u8 {
-        let val = self.get_u8(self.ptr);
+    fn next_u8(&mut self) -> Result<u8, VmError> {
+        let val = self.get_u8(self.ptr)?;
         self.ptr += 1;
-        val
+        Ok(val)
     }
 
-    fn stack_push(&mut self, val: i32) {
-        //println!("Pushed: {}", val);
-        self.stack.push(val);
+    fn load_address(&self, address: i32) -> Result<Value, VmError> {
+        if is_heap_address(address) {
+            Ok(Value::Int(self.heap.load(address, 4)? as i32))
+        } else {
+            self.stack.get_value(address as u32 as usize)
+        }
+    }
+
+    // References written into the byte heap lose their tag and are no longer roots.
+    fn store_address(&mut self, address: i32, val: Value) -> Result<(), VmError> {
+        if is_heap_address(address) {
+            self.heap.store(address, 4, val.raw() as u32)
+        } else {
+            self.stack.set_value(address as u32 as usize, val)
+        }
+    }
+
+    pub fn object(&self, val: Value) -> Result<&Object, VmError> {
+        self.objects.get(val)
+    }
+
+    // Objects only held by the host are not seen by the collector unless pinned.
+    pub fn pin(&mut self, val: Value) {
+        self.pinned.push(val);
+    }
+
+    pub fn unpin(&mut self, val: Value) {
+        if let Some(index) = self.pinned.iter().position(|pinned| *pinned == val) {
+            self.pinned.swap_remove(index);
+        }
+    }
+
+    pub fn collect_garbage(&mut self) {
+        let active = self.coroutines.iter().map(|active| active.object);
+        let pinned = self.pinned.iter().copied();
+        let roots = active.chain(pinned).filter_map(|val| match val {
+            Value::Ref(index) => Some(index),
+            Value::Int(_) => None,
+        });
+        self.objects.collect(self.stack.roots().chain(roots));
     }
 
-    fn stack_pop(&mut self) -> i32 {
-        let val = self.stack.pop();
-        //println!("Poped: {}", val);
-        val
+    fn alloc_object(&mut self, object: Object) -> Result<Value, VmError> {
+        let val = self.objects.alloc(object);
+        self.stack.push_value(val)?;
+        Ok(val)
     }
-}
\ No newline at end of file
+
+    // Pops a format string and one argument per `{}` in it, pushing the formatted string.
+    fn format(&mut self) -> Result<(), VmError> {
+        self.maybe_collect_garbage();
+        let fmt = match self.objects.get(self.stack.pop_value()?)? {
+            Object::String(str) => str.clone(),
+            obj => return Err(type_mismatch("a string", obj)),
+        };
+
+        let pieces: Vec<&str> = fmt.split("{}").collect();
+        let mut args = Vec::with_capacity(pieces.len() - 1);
+        for _ in 1..pieces.len() {
+            args.push(self.stack.pop_value()?);
+        }
+
+        let mut out = String::from(pieces[0]);
+        for (piece, arg) in pieces[1..].iter().zip(args.iter().rev()) {
+            match arg {
+                Value::Int(val) => out += &val.to_string(),
+                Value::Ref(_) => match self.objects.get(*arg)? {
+                    Object::String(str) => out += str,
+                    obj => out += &format!("{:?}", obj),
+                },
+            }
+            out += piece;
+        }
+
+        self.alloc_object(Object::String(out))?;
+        Ok(())
+    }
+
+    fn maybe_collect_garbage(&mut self) {
+        if self.objects.should_collect() {
+            self.collect_garbage();
+        }
+    }
+
+    // Walks the frames outwards from the throwing instruction until a handler covers
+    // the pc, then cuts the stack back to that handler's depth and jumps to it.
+    fn throw(&mut self, val: Value) -> Result<(), VmError> {
+        let mut pc = self.ins_ptr;
+        let mut frame_ptr = self.frame_ptr;
+        loop {
+            let handler = self.program.handlers().iter()
+                .filter(|handler| handler.start as usize <= pc && pc < handler.end as usize)
+                .max_by_key(|handler| handler.start)
+                .copied();
+
+            if let Some(handler) = handler {
+                let base = if frame_ptr == 0 { self.stack.stack.len() - 1 } else { frame_ptr };
+                let stack_ptr = base.checked_sub(handler.depth as usize).ok_or(VmError::StackOverflow)?;
+                while self.stack.ptr < stack_ptr {
+                    self.stack.pop_value()?;
+                }
+                // Slots the handler expects but the stack no longer holds read as zero.
+                while self.stack.ptr > stack_ptr {
+                    self.stack.push_value(Value::Int(0))?;
+                }
+                self.frame_ptr = frame_ptr;
+                self.stack.push_value(val)?;
+                self.ptr = handler.target as usize;
+                return Ok(());
+            }
+
+            if frame_ptr == 0 {
+                return Err(VmError::Uncaught(val));
+            }
+            let caller = self.stack.get(frame_ptr + 1)? as u32 as usize;
+            // A coroutine starts inside its function's frame, which no code called.
+            if caller == 0 && !self.coroutines.is_empty() {
+                return Err(VmError::Uncaught(val));
+            }
+            pc = (self.stack.get(frame_ptr + 2)? as u32 as usize).saturating_sub(1);
+            frame_ptr = caller;
+        }
+    }
+
+    fn function_offset(&self, function: i32) -> Result<usize, VmError> {
+        match self.program.functions().get(function as u32 as usize) {
+            Some(function) => Ok(function.offset as usize),
+            None => Err(VmError::InvalidFunction(function)),
+        }
+    }
+
+    fn call(&mut self, destination: usize) -> Result<(), VmError> {
+        self.stack_push(self.ptr as u32 as i32)?;
+        self.stack_push(self.frame_ptr as u32 as i32)?;
+        self.ptr = destination;
+        self.frame_ptr = self.stack.ptr;
+        Ok(())
+    }
+
+    // Replaces the current frame with one for a call taking the `args` values on
+    // top of the stack, keeping the caller's return address and frame pointer.
+    fn tail_call(&mut self, args: usize, params: usize) -> Result<(), VmError> {
+        let frame_ptr = self.frame_ptr;
+        let saved_frame_ptr = self.stack.get_value(frame_ptr + 1)?;
+        let return_address = self.stack.get_value(frame_ptr + 2)?;
+
+        let base = frame_ptr + 2 + params;
+        let top = self.stack.ptr + args;
+        if top >= self.stack.stack.len() || base < args + 2 {
+            return Err(VmError::StackUnderflow);
+        }
+        for i in 0..args {
+            let val = self.stack.get_value(top - i)?;
+            self.stack.set_value(base - i, val)?;
+        }
+        self.stack.set_value(base - args, return_address)?;
+        self.stack.set_value(base - args - 1, saved_frame_ptr)?;
+
+        let new_frame_ptr = base - args - 2;
+        for index in self.stack.ptr + 1..=new_frame_ptr {
+            self.stack.set_value(index, Value::Int(0))?;
+        }
+        self.stack.ptr = new_frame_ptr;
+        self.frame_ptr = new_frame_ptr;
+        Ok(())
+    }
+
+    fn stack_push(&mut self, val: i32) -> Result<(), VmError> {
+        self.stack.push(val)
+    }
+
+    fn stack_pop(&mut self) -> Result<i32, VmError> {
+        self.stack.pop()
+    }
+
+    // Pops `count` values, returning them in the order they were pushed. The count
+    // comes from the code, so it is checked against the stack before anything is
+    // reserved for it.
+    fn pop_values(&mut self, count: i32) -> Result<Vec<Value>, VmError> {
+        if count < 0 {
+            return Err(VmError::NegativeLength(count));
+        }
+        if count as usize > self.stack.depth() {
+            return Err(VmError::StackUnderflow);
+        }
+        let mut values = (0..count).map(|_| self.stack.pop_value()).collect::<Result<Vec<_>, _>>()?;
+        values.reverse();
+        Ok(values)
+    }
+}
@@ -20,9 +20,46 @@
  */
 
 pub mod interpreter;
+pub mod heap;
+pub mod gc;
+pub mod module;
+pub mod error;
+pub mod debug_info;
+pub mod scheduler;
+pub mod program;
+pub mod snapshot;
+pub mod hook;
+pub mod profiler;
+pub mod trace;
+pub mod coverage;
+pub mod replay;
+pub mod debugger;
+pub mod json;
+pub mod dap;
+pub mod gdb;
+pub mod asm;
 
+#[cfg(test)]
 mod tests {
-    use crate::interpreter::{Interpreter, Instruction, InstructionList};
+    use crate::interpreter::{Interpreter, Instruction, InstructionList, CompilerCall, CoroutineResult};
+    use crate::gc::{Object, Value};
+    use crate::module::{Constant, Module, ModuleError};
+    use crate::debug_info::DebugInfo;
+    use crate::error::VmError;
+    use crate::scheduler::{Scheduler, TaskState};
+    use crate::program::{Program, VerifyError};
+    use crate::snapshot::{Snapshot, SnapshotError};
+    use crate::hook::Tracer;
+    use crate::profiler::Profiler;
+    use crate::trace::{TraceRecorder, TracePhase, VirtualClock};
+    use crate::coverage::{BranchCoverage, Coverage};
+    use crate::replay::{Event, ExecutionLog, ReplayError};
+    use crate::debugger::{Debugger, StopReason, WatchKind};
+    use crate::dap::Session;
+    use crate::json::Json;
+    use crate::gdb::{self, GdbStub, Input};
+    use crate::asm::{AsmError, Assembler};
+    use std::sync::Arc;
 
 
     #[test]
@@ -33,7 +70,7 @@ mod tests {
             u8::from(Instruction::I32Add), 
             u8::from(Instruction::CompilerCall), 1, 0, 0, 0,
             u8::from(Instruction::Hlt)]);
-        int.run();
+        int.run().unwrap();
     }
 
     #[test]
@@ -49,6 +86,1553 @@ mod tests {
         instrs.push_instruction(Instruction::Hlt);
 
         let mut int = Interpreter::new(instrs.code);
-        int.run();
+        int.run().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_heap() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(8);
+        instrs.push_instruction(Instruction::Alloc);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(1000);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0x1234);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1000);
+        instrs.push_instruction(Instruction::Store16);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0xAB);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1000);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::Store8);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1000);
+        instrs.push_instruction(Instruction::Deref);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let mut int = Interpreter::new(instrs.code);
+        int.run().unwrap();
+        assert_eq!(int.peek(), 0xAB1234);
+    }
+
+    #[test]
+    fn test_heap_out_of_bounds() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::Alloc);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::Load8);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let mut int = Interpreter::new(instrs.code);
+        let trap = int.run().unwrap_err();
+        assert!(matches!(trap.error, VmError::HeapOutOfBounds { width: 1, .. }));
+    }
+
+    #[test]
+    fn test_objects() {
+        let mut instrs = InstructionList::new();
+        // [0, 0, 0][1] = 42
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::NewArray);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(42);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::SetElem);
+        // { 7, array }.1[1]
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(7);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::NewRecord);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::GetField);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::GetElem);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let mut int = Interpreter::new(instrs.code);
+        int.run().unwrap();
+        assert_eq!(int.peek_value(), Value::Int(42));
+        assert_eq!(int.live_objects(), 2);
+
+        // Counts come from the code and are checked before anything is reserved.
+        for (count, error) in [(-1, VmError::NegativeLength(-1)), (2_000_000_000, VmError::StackUnderflow)] {
+            let mut instrs = InstructionList::new();
+            instrs.push_instruction(Instruction::NewRecord);
+            instrs.push_i32_operand(count);
+            let trap = Interpreter::new(instrs.code).run().unwrap_err();
+            assert_eq!(trap.error, error);
+        }
+    }
+
+    #[test]
+    fn test_gc() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::NewArray);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::NewArray);
+        instrs.push_instruction(Instruction::Pop);
+        instrs.push_instruction(Instruction::Gc);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let mut int = Interpreter::new(instrs.code);
+        int.run().unwrap();
+        assert!(matches!(int.peek_value(), Value::Ref(_)));
+        assert_eq!(int.live_objects(), 1);
+    }
+
+    #[test]
+    fn test_constants() {
+        let mut instrs = InstructionList::new();
+        let fmt = instrs.add_string_constant("{} + {} = {}");
+        let name = instrs.add_string_constant("two");
+        assert_eq!(instrs.add_string_constant("two"), name);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::PushConst);
+        instrs.push_i32_operand(name as i32);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::PushConst);
+        instrs.push_i32_operand(fmt as i32);
+        instrs.push_instruction(Instruction::CompilerCall);
+        instrs.push_i32_operand(u8::from(CompilerCall::Format) as i32);
+        instrs.push_instruction(Instruction::CompilerCall);
+        instrs.push_i32_operand(u8::from(CompilerCall::PrintStr) as i32);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let module = Module::from_bytes(&instrs.into_module().to_bytes()).unwrap();
+        let mut int = Interpreter::from_module(module);
+        int.run().unwrap();
+        assert_eq!(int.object(int.peek_value()).unwrap(), &Object::String("1 + two = 3".to_string()));
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(7);
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Hlt);
+
+        // fn sub(a, b) { let c = a - b; return c; }
+        instrs.set_i32_operand(instrs.len() as i32, call);
+        instrs.push_instruction(Instruction::Enter);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::I32Sub);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::Leave);
+        instrs.push_instruction(Instruction::RetN);
+        instrs.push_i32_operand(2);
+
+        let mut int = Interpreter::new(instrs.code);
+        int.run().unwrap();
+        assert_eq!(int.peek(), 5 - 7);
+        assert_eq!(int.stack_depth(), 1);
+    }
+
+    #[test]
+    fn test_backtrace() {
+        let mut instrs = InstructionList::new();
+        instrs.add_function("main");
+        instrs.push_instruction(Instruction::Call);
+        let call_outer = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Hlt);
+
+        instrs.set_i32_operand(instrs.len() as i32, call_outer);
+        instrs.add_function("outer");
+        instrs.push_instruction(Instruction::Call);
+        let call_inner = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Ret);
+
+        instrs.set_i32_operand(instrs.len() as i32, call_inner);
+        instrs.add_function("inner");
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        let faulting = instrs.len();
+        instrs.push_instruction(Instruction::I32Div);
+        instrs.push_instruction(Instruction::Ret);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        let trap = int.run().unwrap_err();
+        assert_eq!(trap.error, VmError::DivideByZero);
+        let frames: Vec<_> = trap.backtrace.frames.iter().map(|frame| frame.function.as_deref()).collect();
+        assert_eq!(frames, [Some("inner"), Some("outer"), Some("main")]);
+        assert_eq!(trap.backtrace.frames[0].pc, faulting);
+        assert!(trap.to_string().starts_with("error: division by zero\n  0: at"));
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let mut instrs = InstructionList::new();
+        instrs.set_location("main.src", 1, 1);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.set_location("main.src", 2, 5);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.set_location("main.src", 3, 5);
+        instrs.push_instruction(Instruction::I32Div);
+
+        let module = Module::from_bytes(&instrs.into_module().to_bytes()).unwrap();
+        let disassembly = module.disassembly().to_string();
+        assert!(disassembly.contains("; main.src:2:5\n5 : Push 1\n; main.src:3:5\n10 : I32Div"));
+
+        let mut int = Interpreter::from_module(module);
+        let trap = int.run().unwrap_err();
+        let location = trap.backtrace.frames[0].location.as_ref().unwrap();
+        assert_eq!((location.file.as_str(), location.line, location.column), ("main.src", 3, 5));
+        assert!(trap.to_string().contains("at 10 (main.src:3:5)"));
+
+        // Offsets past u32 are rejected, entries out of order are written sorted.
+        let overflow = [0, 2, 0xff, 0xff, 0xff, 0xff, 0x0f, 0, 0, 0, 1, 0, 0, 0];
+        assert_eq!(DebugInfo::from_bytes(&overflow), Err(ModuleError::InvalidDebugInfo));
+        assert_eq!(DebugInfo::from_bytes(&[0, 1, 0x80, 0x80, 0x80, 0x80, 0x10, 0, 0, 0]), Err(ModuleError::InvalidDebugInfo));
+        let mut debug_info = DebugInfo::default();
+        debug_info.add_location(8, "a.src", 2, 1);
+        debug_info.add_location(0, "a.src", 1, 1);
+        let sorted = DebugInfo::from_bytes(&debug_info.to_bytes()).unwrap();
+        assert_eq!(sorted.lines.iter().map(|entry| entry.offset).collect::<Vec<_>>(), [0, 8]);
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(5000);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Hlt);
+
+        // fn sum(n, acc) { if n == 0 { acc } else { sum(n - 1, acc + n) } }
+        let sum = instrs.len() as i32;
+        instrs.set_i32_operand(sum, call);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::Jz);
+        let done = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::I32Sub);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::TailCall);
+        instrs.push_i32_operand(sum);
+        instrs.push_u8_operand(2);
+        instrs.push_u8_operand(2);
+        instrs.set_i32_operand(instrs.len() as i32, done);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::RetN);
+        instrs.push_i32_operand(2);
+
+        let module = instrs.into_module();
+        assert!(module.disassembly().to_string().contains("TailCall 21, 2, 2"));
+
+        let mut int = Interpreter::from_module(module);
+        int.run().unwrap();
+        assert_eq!(int.peek(), 5000 * 5001 / 2);
+        assert_eq!(int.stack_depth(), 1);
+    }
+
+    #[test]
+    fn test_call_indirect() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        let function = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::CallIndirect);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(7);
+        instrs.push_instruction(Instruction::CallIndirect);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let forty_two = instrs.add_function("forty_two");
+        instrs.set_i32_operand(forty_two as i32, function);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(42);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::Ret);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        let trap = int.run().unwrap_err();
+        assert_eq!(trap.error, VmError::InvalidFunction(7));
+        assert_eq!(int.peek(), 42);
+    }
+
+    #[test]
+    fn test_closures() {
+        let mut instrs = InstructionList::new();
+        // let add = |x| x + captured; add(5)
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(37);
+        instrs.push_instruction(Instruction::Push);
+        let function = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::MakeClosure);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::CallClosure);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let add = instrs.add_function("add");
+        instrs.set_i32_operand(add as i32, function);
+        instrs.push_instruction(Instruction::LoadUpvalue);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::RetN);
+        instrs.push_i32_operand(2);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.run().unwrap();
+        assert_eq!(int.peek(), 42);
+        assert_eq!(int.stack_depth(), 1);
+
+        for (count, error) in [(-1, VmError::NegativeLength(-1)), (2_000_000_000, VmError::StackUnderflow)] {
+            let mut instrs = InstructionList::new();
+            instrs.push_instruction(Instruction::Push);
+            let function = instrs.len();
+            instrs.push_i32_operand(0);
+            instrs.push_instruction(Instruction::MakeClosure);
+            instrs.push_i32_operand(count);
+            instrs.push_instruction(Instruction::Hlt);
+            let f = instrs.add_function("f");
+            instrs.set_i32_operand(f as i32, function);
+            instrs.push_instruction(Instruction::Ret);
+            let trap = Interpreter::from_module(instrs.into_module()).run().unwrap_err();
+            assert_eq!(trap.error, error);
+        }
+    }
+
+    #[test]
+    fn test_jnz() {
+        // n = 3; steps = 0; do { steps += 1; n -= 1 } while n != 0
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(3);
+        let body = instrs.len();
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1022);
+        instrs.push_instruction(Instruction::I32Sub);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(1022);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1022);
+        instrs.push_instruction(Instruction::Jnz);
+        instrs.push_i32_operand(body as i32);
+        instrs.push_instruction(Instruction::Pop);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let mut int = Interpreter::new(instrs.code);
+        int.run().unwrap();
+        assert_eq!(int.peek(), 3);
+        assert_eq!(int.stack_depth(), 1);
+    }
+
+    #[test]
+    fn test_exceptions() {
+        let mut instrs = InstructionList::new();
+        let start = instrs.len();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        let end = instrs.len();
+        instrs.push_instruction(Instruction::Hlt);
+
+        let handler = instrs.len();
+        instrs.add_handler(start, end, handler, 0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(-1);
+        instrs.push_instruction(Instruction::Throw);
+
+        instrs.set_i32_operand(instrs.len() as i32, call);
+        instrs.push_instruction(Instruction::Enter);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(41);
+        instrs.push_instruction(Instruction::Throw);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        let trap = int.run().unwrap_err();
+        assert_eq!(trap.error, VmError::Uncaught(Value::Int(-1)));
+        assert_eq!(trap.backtrace.frames.len(), 1);
+        assert_eq!(int.stack_depth(), 1);
+        assert_eq!(int.peek(), 42);
+
+        // A coroutine's function was not called from the end of the code.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        let function = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::CoCreate);
+        instrs.push_instruction(Instruction::Resume);
+        instrs.push_instruction(Instruction::Hlt);
+        let thrower = instrs.add_function("thrower");
+        instrs.set_i32_operand(thrower as i32, function);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::Throw);
+        let tail = instrs.len();
+        instrs.push_instruction(Instruction::Hlt);
+        instrs.add_handler(tail, instrs.len(), tail, 0);
+        let trap = Interpreter::from_module(instrs.into_module()).run().unwrap_err();
+        assert_eq!(trap.error, VmError::Uncaught(Value::Int(5)));
+
+        // Slots a handler expects beyond the stack are zeroed, not left stale.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(7);
+        instrs.push_instruction(Instruction::Pop);
+        let start = instrs.len();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Throw);
+        let handler = instrs.len();
+        instrs.push_instruction(Instruction::Hlt);
+        instrs.add_handler(start, handler, handler, 1);
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.run().unwrap();
+        assert_eq!(int.stack_depth(), 2);
+        assert_eq!(int.stack_value(1023).unwrap(), Value::Int(0));
+    }
+
+    fn generator(instrs: &mut InstructionList) -> u32 {
+        // fn gen() { yield 1; yield 2; yield 3; }
+        let gen = instrs.add_function("gen");
+        instrs.push_instruction(Instruction::Pop);
+        for i in 1..=3 {
+            instrs.push_instruction(Instruction::Push);
+            instrs.push_i32_operand(i);
+            instrs.push_instruction(Instruction::Yield);
+            instrs.push_instruction(Instruction::Pop);
+        }
+        instrs.push_instruction(Instruction::Ret);
+        gen
+    }
+
+    #[test]
+    fn test_coroutines() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        let function = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::CoCreate);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(0);
+        for _ in 0..4 {
+            instrs.push_instruction(Instruction::Push);
+            instrs.push_i32_operand(0);
+            instrs.push_instruction(Instruction::Load);
+            instrs.push_i32_operand(0);
+            instrs.push_instruction(Instruction::Resume);
+        }
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::CoStatus);
+        instrs.push_instruction(Instruction::Hlt);
+        let gen = generator(&mut instrs);
+        instrs.set_i32_operand(gen as i32, function);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.run().unwrap();
+        assert_eq!(int.peek(), 2);
+        assert_eq!(int.stack_depth(), 2);
+    }
+
+    #[test]
+    fn test_host_resume() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Hlt);
+        let gen = generator(&mut instrs);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        let coroutine = int.create_coroutine(gen).unwrap();
+        int.pin(coroutine);
+        let mut results = Vec::new();
+        for _ in 0..4 {
+            results.push(int.resume(coroutine, Value::Int(0)).unwrap());
+        }
+        assert_eq!(results, [
+            CoroutineResult::Yielded(Value::Int(1)),
+            CoroutineResult::Yielded(Value::Int(2)),
+            CoroutineResult::Yielded(Value::Int(3)),
+            CoroutineResult::Finished(Value::Int(0)),
+        ]);
+        let trap = int.resume(coroutine, Value::Int(0)).unwrap_err();
+        assert!(matches!(trap.error, VmError::CoroutineNotSuspended(_)));
+
+        // A coroutine created before code was appended still finishes when it returns.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Hlt);
+        let gen = generator(&mut instrs);
+        let module = instrs.into_module();
+        let mut int = Interpreter::with_program(Arc::new(Program::new(module.clone()).unwrap()));
+        let coroutine = int.create_coroutine(gen).unwrap();
+        int.pin(coroutine);
+        let mut appended = module;
+        appended.code.extend([u8::from(Instruction::Push), 99, 0, 0, 0, u8::from(Instruction::Hlt)]);
+        int.set_program(Arc::new(Program::new(appended).unwrap())).unwrap();
+        let mut result = int.resume(coroutine, Value::Int(0)).unwrap();
+        while let CoroutineResult::Yielded(_) = result {
+            result = int.resume(coroutine, Value::Int(0)).unwrap();
+        }
+        assert_eq!(result, CoroutineResult::Finished(Value::Int(0)));
+    }
+
+    // fn name() { sleep(ticks); count down from `iterations`; return result }
+    fn task(instrs: &mut InstructionList, name: &str, ticks: i32, iterations: i32, result: i32) -> u32 {
+        let function = instrs.add_function(name);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(ticks);
+        instrs.push_instruction(Instruction::Sleep);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(iterations);
+        let head = instrs.len();
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Jz);
+        let done = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::I32Sub);
+        instrs.push_instruction(Instruction::Jmp);
+        instrs.push_i32_operand(head as i32);
+        instrs.set_i32_operand(instrs.len() as i32, done);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(result);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::Ret);
+        function
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let mut instrs = InstructionList::new();
+        let slow = task(&mut instrs, "slow", 5, 200, 10);
+        let fast = task(&mut instrs, "fast", 0, 10, 32);
+
+        let main = instrs.add_function("main");
+        for function in [slow, fast] {
+            instrs.push_instruction(Instruction::Push);
+            instrs.push_i32_operand(function as i32);
+            instrs.push_instruction(Instruction::Spawn);
+        }
+        instrs.push_instruction(Instruction::Join);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Join);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::Ret);
+
+        let module = instrs.into_module();
+        let run = || {
+            let mut scheduler = Scheduler::new(module.clone(), 16);
+            let main = scheduler.spawn(main).unwrap();
+            scheduler.run().unwrap();
+            assert_eq!(scheduler.state(main), Some(&TaskState::Finished(Value::Int(42))));
+            assert_eq!(scheduler.state(2), Some(&TaskState::Finished(Value::Int(32))));
+            scheduler.tick()
+        };
+        let ticks = run();
+        assert!(ticks > 10);
+        assert_eq!(run(), ticks);
+    }
+
+    #[test]
+    fn test_scheduler_deadlock() {
+        let mut instrs = InstructionList::new();
+        let main = instrs.add_function("main");
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Join);
+        instrs.push_instruction(Instruction::Ret);
+
+        let mut scheduler = Scheduler::new(instrs.into_module(), 16);
+        scheduler.spawn(main).unwrap();
+        assert_eq!(scheduler.run(), Err(VmError::Deadlock));
+    }
+
+    #[test]
+    fn test_host_channels() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(0);
+        let head = instrs.len();
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Recv);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::Jz);
+        let done = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::I32Mul);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Send);
+        instrs.push_instruction(Instruction::Jmp);
+        instrs.push_i32_operand(head as i32);
+        instrs.set_i32_operand(instrs.len() as i32, done);
+        instrs.push_instruction(Instruction::Hlt);
+
+        let mut int = Interpreter::new(instrs.code);
+        let (input, input_handle) = int.create_channel();
+        let (output, output_handle) = int.create_channel();
+        int.push_value(input).unwrap();
+        int.push_value(output).unwrap();
+
+        for i in 1..=3 {
+            input_handle.send(i);
+        }
+        let trap = int.run().unwrap_err();
+        assert!(matches!(trap.error, VmError::WouldBlock(_)));
+        assert_eq!(output_handle.drain(), [Value::Int(2), Value::Int(4), Value::Int(6)]);
+
+        input_handle.send(0);
+        int.run().unwrap();
+        assert!(output_handle.is_empty());
+    }
+
+    #[test]
+    fn test_task_channels() {
+        let mut instrs = InstructionList::new();
+        // fn producer(ch) { for i in 1..=3 { send(ch, i); sleep(1) } }
+        let producer = instrs.add_function("producer");
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(0);
+        for i in 1..=3 {
+            instrs.push_instruction(Instruction::Push);
+            instrs.push_i32_operand(i);
+            instrs.push_instruction(Instruction::Load);
+            instrs.push_i32_operand(0);
+            instrs.push_instruction(Instruction::Send);
+            instrs.push_instruction(Instruction::Push);
+            instrs.push_i32_operand(1);
+            instrs.push_instruction(Instruction::Sleep);
+        }
+        instrs.push_instruction(Instruction::Ret);
+        // fn consumer(ch) { recv(ch) + recv(ch) + recv(ch) }
+        let consumer = instrs.add_function("consumer");
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(0);
+        for _ in 0..3 {
+            instrs.push_instruction(Instruction::Load);
+            instrs.push_i32_operand(0);
+            instrs.push_instruction(Instruction::Recv);
+        }
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::Ret);
+
+        let mut scheduler = Scheduler::new(instrs.into_module(), 64);
+        let (channel, _) = scheduler.interpreter_mut().create_channel();
+        let consumer = scheduler.spawn_with(consumer, channel).unwrap();
+        scheduler.spawn_with(producer, channel).unwrap();
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.state(consumer), Some(&TaskState::Finished(Value::Int(6))));
+    }
+
+    #[test]
+    fn test_shared_program() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Interpreter>();
+
+        // The host pushes x, the program leaves x * x on top of it.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::I32Mul);
+        instrs.push_instruction(Instruction::Hlt);
+        let program = Arc::new(Program::new(instrs.into_module()).unwrap());
+
+        let results: Vec<i32> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (1..=4).map(|x| {
+                let program = program.clone();
+                scope.spawn(move || {
+                    let mut int = Interpreter::with_program(program);
+                    int.push_value(Value::Int(x)).unwrap();
+                    int.run().unwrap();
+                    int.peek()
+                })
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        assert_eq!(results, [1, 4, 9, 16]);
+        assert_eq!(Arc::strong_count(&program), 1);
+    }
+
+    #[test]
+    fn test_verify() {
+        let truncated = Module::new(vec![u8::from(Instruction::Push), 1, 0]);
+        assert_eq!(Program::new(truncated).unwrap_err(), VerifyError::TruncatedOperand { offset: 0 });
+
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Jmp);
+        instrs.push_i32_operand(2);
+        instrs.push_instruction(Instruction::Hlt);
+        assert_eq!(Program::new(instrs.into_module()).unwrap_err(), VerifyError::InvalidJump { offset: 0, target: 2 });
+
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::PushConst);
+        instrs.push_i32_operand(0);
+        assert_eq!(Program::new(instrs.into_module()).unwrap_err(), VerifyError::InvalidConstant { offset: 0, index: 0 });
+
+        assert_eq!(Program::new(Module::new(vec![200])).unwrap_err(), VerifyError::InvalidOpcode { offset: 0, opcode: 200 });
+
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::NewRecord);
+        instrs.push_i32_operand(-1);
+        assert_eq!(Program::new(instrs.into_module()).unwrap_err(), VerifyError::InvalidCount { offset: 0, count: -1 });
+
+        // Code that does not verify still runs from a module, trapping at the fault.
+        let trap = Interpreter::new(vec![u8::from(Instruction::Nop), 200]).run().unwrap_err();
+        assert_eq!(trap.error, VmError::InvalidInstruction(200));
+        assert_eq!(trap.backtrace.frames[0].pc, 1);
+        let trap = Interpreter::new(vec![u8::from(Instruction::Push), 1, 0]).run().unwrap_err();
+        assert_eq!(trap.error, VmError::CodeOutOfBounds(3));
+
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(i32::MAX);
+        instrs.push_instruction(Instruction::NewArray);
+        let trap = Interpreter::from_module(instrs.into_module()).run().unwrap_err();
+        assert!(matches!(trap.error, VmError::HeapExhausted(_)));
+    }
+
+    // sum = 0; i = 10; name = "sum"; block = alloc(8); while i != 0 { sum += i; i -= 1 }; sum
+    fn sum_loop() -> (Module, usize) {
+        let mut instrs = InstructionList::new();
+        let name = instrs.add_string_constant("sum");
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(10);
+        instrs.push_instruction(Instruction::PushConst);
+        instrs.push_i32_operand(name as i32);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(8);
+        instrs.push_instruction(Instruction::Alloc);
+        let head = instrs.len();
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1022);
+        instrs.push_instruction(Instruction::Jz);
+        let exit = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1022);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(-1);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1022);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::Store);
+        instrs.push_i32_operand(1022);
+        instrs.push_instruction(Instruction::Jmp);
+        instrs.push_i32_operand(head as i32);
+        instrs.set_i32_operand(instrs.len() as i32, exit);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Hlt);
+        (instrs.into_module(), head)
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let (module, _) = sum_loop();
+        let mut expected = Interpreter::from_module(module.clone());
+        expected.run().unwrap();
+
+        let mut int = Interpreter::from_module(module.clone());
+        int.set_fuel(Some(30));
+        assert_eq!(int.run().unwrap_err().error, VmError::OutOfFuel);
+        let bytes = int.snapshot().into_bytes();
+
+        let program = Arc::new(Program::new(module).unwrap());
+        let mut restored = Interpreter::restore(program.clone(), &Snapshot::from_bytes(bytes.clone()).unwrap()).unwrap();
+        assert_eq!(restored.fuel(), Some(0));
+        restored.set_fuel(None);
+        restored.run().unwrap();
+        assert_eq!(restored.snapshot(), expected.snapshot());
+        assert_eq!(restored.peek(), 55);
+
+        let other = Arc::new(Program::new(Module::new(vec![u8::from(Instruction::Hlt)])).unwrap());
+        let mismatch = Interpreter::restore(other, &Snapshot::from_bytes(bytes.clone()).unwrap()).err();
+        assert!(matches!(mismatch, Some(SnapshotError::ProgramMismatch { .. })));
+        assert_eq!(Snapshot::from_bytes(bytes[..4].to_vec()).unwrap_err(), SnapshotError::Truncated);
+
+        // Header, then the stack length, pointer and 5 bytes a slot, then three pointers and the flags.
+        let fresh = Interpreter::with_program(program.clone()).snapshot().into_bytes();
+        let stack_end = 30 + crate::interpreter::STACK_SIZE * 5;
+        let mut short_stack = fresh[..14].to_vec();
+        short_stack.extend_from_slice(&1u64.to_le_bytes());
+        short_stack.extend_from_slice(&0u64.to_le_bytes());
+        short_stack.extend_from_slice(&[0; 5]);
+        short_stack.extend_from_slice(&fresh[stack_end..]);
+        let restored = Interpreter::restore(program.clone(), &Snapshot::from_bytes(short_stack).unwrap()).err();
+        assert_eq!(restored, Some(SnapshotError::Corrupt("stack has the wrong size")));
+
+        let heap = stack_end + 33;
+        let mut short_heap = fresh[..heap].to_vec();
+        short_heap.extend_from_slice(&0u64.to_le_bytes());
+        short_heap.extend_from_slice(&fresh[heap + 16..]);
+        let restored = Interpreter::restore(program, &Snapshot::from_bytes(short_heap).unwrap()).err();
+        assert_eq!(restored, Some(SnapshotError::Corrupt("heap is shorter than its reserved base")));
+    }
+
+    #[test]
+    fn test_tracer() {
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Hlt);
+        let function = instrs.len();
+        instrs.set_i32_operand(function as i32, call);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(7);
+        instrs.push_instruction(Instruction::CompilerCall);
+        instrs.push_i32_operand(u8::from(CompilerCall::PrintInt) as i32);
+        instrs.push_instruction(Instruction::I32Div);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.set_hook(Tracer::new(Vec::new()));
+        assert_eq!(int.run().unwrap_err().error, VmError::DivideByZero);
+        let trace = String::from_utf8(int.take_hook::<Tracer<Vec<u8>>>().unwrap().into_inner()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines[..8], [
+            "0 : Push 0 ; depth 1, top 0",
+            "5 : Call 11 ; depth 3, top 0",
+            "  call 5 -> 11, fp 1020",
+            "11 : Push 0 ; depth 4, top 0",
+            "16 : Push 7 ; depth 5, top 7",
+            "21 : CompilerCall 1 ; depth 5, top 7",
+            "  host call PrintInt",
+            "26 : I32Div",
+        ]);
+        assert_eq!(lines[8], "error: division by zero");
+        assert!(int.take_hook::<Tracer<Vec<u8>>>().is_none());
+    }
+
+    #[test]
+    fn test_profiler() {
+        let mut instrs = InstructionList::new();
+        instrs.add_function("main");
+        let mut calls = Vec::new();
+        for arg in [21, 4] {
+            instrs.push_instruction(Instruction::Push);
+            instrs.push_i32_operand(0);
+            instrs.push_instruction(Instruction::Push);
+            instrs.push_i32_operand(arg);
+            instrs.push_instruction(Instruction::Call);
+            calls.push(instrs.len());
+            instrs.push_i32_operand(0);
+        }
+        instrs.push_instruction(Instruction::Hlt);
+        // fn twice(x) { x + x }
+        instrs.add_function("twice");
+        for call in calls {
+            instrs.set_i32_operand(instrs.len() as i32, call);
+        }
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::RetN);
+        instrs.push_i32_operand(1);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.set_hook(Profiler::new());
+        int.run().unwrap();
+        assert_eq!(int.peek(), 8);
+        let profiler = int.hook::<Profiler>().unwrap();
+
+        let main = profiler.function("main").unwrap();
+        assert_eq!((main.calls, main.self_instructions, main.total_instructions), (1, 7, 17));
+        assert!(main.total_time >= main.self_time);
+        let twice = profiler.function("twice").unwrap();
+        assert_eq!((twice.calls, twice.self_instructions, twice.total_instructions), (2, 10, 10));
+
+        assert_eq!(profiler.opcode_counts()[0].1, 4);
+        assert_eq!(profiler.offset_count(31), 2);
+        assert_eq!(profiler.folded(), "main 7\nmain;twice 10\n");
+        assert!(profiler.report().to_string().contains("twice"));
+    }
+
+    #[test]
+    fn test_trace_events() {
+        let mut instrs = InstructionList::new();
+        instrs.add_function("main");
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(21);
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::CompilerCall);
+        instrs.push_i32_operand(u8::from(CompilerCall::PrintInt) as i32);
+        instrs.push_instruction(Instruction::Hlt);
+        // fn twice(x) { x + x }
+        instrs.add_function("twice \"quoted\"");
+        instrs.set_i32_operand(instrs.len() as i32, call);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::LoadRelative);
+        instrs.push_i32_operand(3);
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(4);
+        instrs.push_instruction(Instruction::RetN);
+        instrs.push_i32_operand(1);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.set_hook(TraceRecorder::with_clock(VirtualClock::new(1000)));
+        int.run().unwrap();
+        let recorder = int.hook::<TraceRecorder<VirtualClock>>().unwrap();
+
+        let events: Vec<(&str, TracePhase, u64)> = recorder.events().iter()
+            .map(|event| (event.name.as_str(), event.phase, event.timestamp))
+            .collect();
+        assert_eq!(events, [
+            ("main", TracePhase::Begin, 0),
+            ("twice \"quoted\"", TracePhase::Begin, 3000),
+            ("twice \"quoted\"", TracePhase::End, 8000),
+            ("PrintInt", TracePhase::Begin, 8000),
+            ("PrintInt", TracePhase::End, 9000),
+        ]);
+        let json = recorder.to_json();
+        assert!(json.contains(r#"{"name":"twice \"quoted\"","cat":"call","ph":"B","ts":3.000,"pid":1,"tid":1}"#));
+        assert!(json.contains(r#"{"name":"main","cat":"call","ph":"E","ts":10.000,"pid":1,"tid":1}"#));
+
+        // A frame entered inside a coroutine ends when the coroutine yields.
+        let mut instrs = InstructionList::new();
+        instrs.add_function("main");
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        let function = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::CoCreate);
+        instrs.push_instruction(Instruction::Resume);
+        instrs.push_instruction(Instruction::Hlt);
+        let outer = instrs.add_function("outer");
+        instrs.set_i32_operand(outer as i32, function);
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.add_function("inner");
+        instrs.set_i32_operand(instrs.len() as i32, call);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(1);
+        instrs.push_instruction(Instruction::Yield);
+
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.set_hook(TraceRecorder::with_clock(VirtualClock::new(1000)));
+        int.run().unwrap();
+        assert_eq!(int.peek(), 1);
+        let recorder = int.hook::<TraceRecorder<VirtualClock>>().unwrap();
+        let events: Vec<(&str, TracePhase)> = recorder.events().iter().map(|event| (event.name.as_str(), event.phase)).collect();
+        assert_eq!(events, [("main", TracePhase::Begin), ("inner", TracePhase::Begin), ("inner", TracePhase::End)]);
+
+        // A host call that traps still ends.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::CompilerCall);
+        instrs.push_i32_operand(u8::from(CompilerCall::PrintInt) as i32);
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.set_hook(TraceRecorder::with_clock(VirtualClock::new(1000)));
+        assert_eq!(int.run().unwrap_err().error, VmError::StackOutOfBounds(1024));
+        let recorder = int.hook::<TraceRecorder<VirtualClock>>().unwrap();
+        let events: Vec<(&str, TracePhase, u64)> = recorder.events().iter()
+            .map(|event| (event.name.as_str(), event.phase, event.timestamp))
+            .collect();
+        assert_eq!(events[1..], [("PrintInt", TracePhase::Begin, 0), ("PrintInt", TracePhase::End, 1000)]);
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut instrs = InstructionList::new();
+        // if x != 0 { 200 } else { 100 }
+        instrs.set_location("branch.src", 1, 1);
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Jnz);
+        let jump = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.set_location("branch.src", 2, 5);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(100);
+        instrs.push_instruction(Instruction::Hlt);
+        instrs.set_i32_operand(instrs.len() as i32, jump);
+        instrs.set_location("branch.src", 3, 5);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(200);
+        instrs.push_instruction(Instruction::Hlt);
+        let program = Arc::new(Program::new(instrs.into_module()).unwrap());
+
+        let run = |x: i32| {
+            let mut int = Interpreter::with_program(program.clone());
+            int.set_hook(Coverage::new());
+            int.push_value(Value::Int(x)).unwrap();
+            int.run().unwrap();
+            (int.peek(), int.take_hook::<Coverage>().unwrap())
+        };
+
+        let (result, mut coverage) = run(5);
+        assert_eq!(result, 200);
+        assert_eq!(coverage.branch(5), BranchCoverage { taken: 1, not_taken: 0 });
+        assert_eq!(coverage.hits(10), 0);
+        let summary = coverage.summary(&program);
+        assert_eq!((summary.instructions, summary.branches, summary.lines), ((4, 6), (1, 2), (2, 3)));
+        assert!(coverage.annotated(&program).to_string().contains("   ##### | 10 : Push 100"));
+
+        let (result, other) = run(0);
+        assert_eq!(result, 100);
+        coverage.merge(&other);
+        assert_eq!(coverage.branch(5), BranchCoverage { taken: 1, not_taken: 1 });
+        assert_eq!(coverage.hits(0), 2);
+        let summary = coverage.summary(&program);
+        assert_eq!((summary.instructions, summary.branches, summary.lines), ((6, 6), (2, 2), (3, 3)));
+        let annotated = coverage.annotated(&program).to_string();
+        assert!(annotated.contains("       2 | 5 : Jnz 16 ; taken 1, not taken 1"));
+        assert!(annotated.contains("         | ; branch.src:3:5"));
+    }
+
+    #[test]
+    fn test_record_replay() {
+        // The host hands in a channel, the script adds up the first two values on it.
+        let mut instrs = InstructionList::new();
+        for _ in 0..2 {
+            instrs.push_instruction(Instruction::Load);
+            instrs.push_i32_operand(1023);
+            instrs.push_instruction(Instruction::TryRecv);
+            instrs.push_instruction(Instruction::Pop);
+        }
+        instrs.push_instruction(Instruction::I32Add);
+        instrs.push_instruction(Instruction::CompilerCall);
+        instrs.push_i32_operand(u8::from(CompilerCall::PrintInt) as i32);
+        instrs.push_instruction(Instruction::Hlt);
+        let program = Arc::new(Program::new(instrs.into_module()).unwrap());
+
+        let mut int = Interpreter::with_program(program.clone());
+        int.record();
+        let (channel, handle) = int.create_channel();
+        handle.send(3);
+        handle.send(4);
+        int.push_value(channel).unwrap();
+        int.run().unwrap();
+        assert_eq!(int.peek(), 7);
+        let bytes = int.take_log().unwrap().to_bytes();
+        let log = ExecutionLog::from_bytes(&bytes).unwrap();
+        assert_eq!(log.events()[1], Event::Receive { pc: 5, value: Some(Value::Int(3)) });
+
+        // Nothing is sent this time, the values come from the log.
+        let mut int = Interpreter::with_program(program.clone());
+        int.replay(log.clone()).unwrap();
+        let (channel, _) = int.create_channel();
+        int.push_value(channel).unwrap();
+        int.run().unwrap();
+        assert_eq!(int.peek(), 7);
+        assert!(int.replay_finished());
+
+        let mut int = Interpreter::with_program(program);
+        int.replay(log.clone()).unwrap();
+        int.push_value(Value::Int(0)).unwrap();
+        let error = int.push_value(Value::Int(0)).unwrap_err();
+        assert!(matches!(error, VmError::Diverged { event: 1, .. }));
+
+        let other = Interpreter::from_module(Module::new(vec![u8::from(Instruction::Hlt)])).replay(log).unwrap_err();
+        assert!(matches!(other, ReplayError::ProgramMismatch { .. }));
+
+        // A task's spawn argument comes from the host too.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Hlt);
+        let task = instrs.add_function("task");
+        instrs.push_instruction(Instruction::Hlt);
+        let module = instrs.into_module();
+        let mut scheduler = Scheduler::new(module.clone(), 16);
+        scheduler.interpreter_mut().record();
+        let id = scheduler.spawn_with(task, Value::Int(9)).unwrap();
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.state(id), Some(&TaskState::Finished(Value::Int(9))));
+        let log = scheduler.interpreter_mut().take_log().unwrap();
+        assert_eq!(log.events()[0], Event::HostValue(Value::Int(9)));
+
+        let mut scheduler = Scheduler::new(module, 16);
+        scheduler.interpreter_mut().replay(log).unwrap();
+        let id = scheduler.spawn_with(task, Value::Int(0)).unwrap();
+        scheduler.run().unwrap();
+        assert_eq!(scheduler.state(id), Some(&TaskState::Finished(Value::Int(9))));
+    }
+
+    #[test]
+    fn test_reverse_debugging() {
+        let (module, head) = sum_loop();
+        let mut forward = Interpreter::from_module(module.clone());
+        let mut states = vec![forward.snapshot()];
+        while !forward.single_step().unwrap() {
+            states.push(forward.snapshot());
+        }
+        states.push(forward.snapshot());
+
+        let mut debugger = Debugger::new(Interpreter::from_module(module));
+        debugger.set_checkpoint_interval(16);
+        debugger.add_breakpoint(head);
+        assert_eq!(debugger.continue_(), StopReason::Breakpoint(head));
+        assert_eq!(debugger.continue_(), StopReason::Breakpoint(head));
+        let second = debugger.steps();
+        assert_eq!(debugger.continue_(), StopReason::Breakpoint(head));
+        assert_eq!(debugger.reverse_continue(), StopReason::Breakpoint(head));
+        assert_eq!(debugger.steps(), second);
+        assert_eq!(debugger.interpreter().snapshot(), states[second as usize]);
+
+        debugger.remove_breakpoint(head);
+        assert_eq!(debugger.continue_(), StopReason::Halted);
+        assert_eq!(debugger.interpreter().peek(), 55);
+        let write = debugger.last_write(1023).unwrap();
+        assert_eq!((write.old, write.new), (Value::Int(54), Value::Int(55)));
+        assert_eq!(debugger.interpreter().program().code()[write.pc], u8::from(Instruction::Store));
+
+        // Undo every step, including the allocations that need a checkpoint.
+        while debugger.steps() > 0 {
+            assert_eq!(debugger.step_back(), StopReason::Step);
+            assert_eq!(debugger.interpreter().snapshot(), states[debugger.steps() as usize]);
+        }
+        assert_eq!(debugger.step_back(), StopReason::StartOfHistory);
+
+        // A bounded history is trimmed a checkpoint interval at a time.
+        let mut debugger = Debugger::new(Interpreter::from_module(sum_loop().0));
+        debugger.set_checkpoint_interval(8);
+        debugger.set_history_limit(20);
+        assert_eq!(debugger.continue_(), StopReason::Halted);
+        let end = debugger.steps();
+        assert_eq!(end as usize, states.len() - 1);
+        while debugger.step_back() == StopReason::Step {
+            assert_eq!(debugger.interpreter().snapshot(), states[debugger.steps() as usize]);
+        }
+        assert!((13..=20).contains(&(end - debugger.steps())));
+        assert!(debugger.steps().is_multiple_of(8));
+
+        // Steps run again from a checkpoint take their input from the journal and are
+        // not seen by the hook, the recording loses what was stepped back over.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Load);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::TryRecv);
+        instrs.push_instruction(Instruction::Hlt);
+        let mut int = Interpreter::from_module(instrs.into_module());
+        int.record();
+        let (channel, handle) = int.create_channel();
+        handle.send(3);
+        int.push_value(channel).unwrap();
+        int.set_hook(Coverage::new());
+        let mut debugger = Debugger::new(int);
+        debugger.step();
+        debugger.step();
+        assert_eq!(debugger.step_back(), StopReason::Step);
+        assert_eq!(debugger.interpreter().hook::<Coverage>().unwrap().hits(0), 1);
+        debugger.step();
+        let mut int = debugger.into_interpreter();
+        let log = int.take_log().unwrap();
+        assert_eq!(log.events()[1..], [Event::Receive { pc: 5, value: Some(Value::Int(3)) }]);
+
+        // `DerefAssign` through a heap address is undone by the checkpoint.
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(8);
+        instrs.push_instruction(Instruction::Alloc);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::DerefAssign);
+        instrs.push_i32_operand(1023);
+        instrs.push_instruction(Instruction::Hlt);
+        let mut debugger = Debugger::new(Interpreter::from_module(instrs.into_module()));
+        for _ in 0..3 {
+            debugger.step();
+        }
+        let before = debugger.interpreter().snapshot();
+        assert_eq!(debugger.step(), StopReason::Step);
+        assert_ne!(debugger.interpreter().snapshot(), before);
+        debugger.step_back();
+        assert_eq!(debugger.interpreter().snapshot(), before);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let (module, head) = sum_loop();
+        let mut debugger = Debugger::new(Interpreter::from_module(module));
+        let sum = debugger.watch(1023, WatchKind::Write);
+        let StopReason::Watchpoint(hit) = debugger.continue_() else { panic!("expected a watchpoint") };
+        assert_eq!((hit.id, hit.slot, hit.instruction, hit.pc), (sum, 1023, Instruction::Push, 0));
+        let StopReason::Watchpoint(hit) = debugger.continue_() else { panic!("expected a watchpoint") };
+        assert_eq!(hit.instruction, Instruction::Store);
+        assert_eq!((hit.old, hit.new), (Value::Int(0), Value::Int(10)));
+        let StopReason::Watchpoint(hit) = debugger.continue_() else { panic!("expected a watchpoint") };
+        assert_eq!((hit.old, hit.new), (Value::Int(10), Value::Int(19)));
+
+        debugger.remove_watchpoint(sum);
+        let count = debugger.watch(1022, WatchKind::Read);
+        let StopReason::Watchpoint(hit) = debugger.continue_() else { panic!("expected a watchpoint") };
+        assert_eq!((hit.id, hit.instruction, hit.kind, hit.new), (count, Instruction::Load, WatchKind::Read, Value::Int(9)));
+        let StopReason::Watchpoint(hit) = debugger.continue_() else { panic!("expected a watchpoint") };
+        assert_eq!((hit.pc, hit.old, hit.new), (head, Value::Int(8), Value::Int(8)));
+
+        // fn f() { let x = 7; *&x = 9; }
+        let mut instrs = InstructionList::new();
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Hlt);
+        instrs.set_i32_operand(instrs.len() as i32, call);
+        instrs.push_instruction(Instruction::Enter);
+        instrs.push_i32_operand(1);
+        let body = instrs.len();
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(7);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Lea);
+        instrs.push_i32_operand(0);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(9);
+        let assign = instrs.len();
+        instrs.push_instruction(Instruction::DerefAssign);
+        instrs.push_i32_operand(1020);
+        instrs.push_instruction(Instruction::Leave);
+        instrs.push_instruction(Instruction::Ret);
+
+        let mut debugger = Debugger::new(Interpreter::from_module(instrs.into_module()));
+        debugger.add_breakpoint(body);
+        assert_eq!(debugger.continue_(), StopReason::Breakpoint(body));
+        let local = debugger.watch_local(0, WatchKind::Write);
+        let StopReason::Watchpoint(hit) = debugger.continue_() else { panic!("expected a watchpoint") };
+        assert_eq!((hit.instruction, hit.old, hit.new), (Instruction::StoreRelative, Value::Int(0), Value::Int(7)));
+        let StopReason::Watchpoint(hit) = debugger.continue_() else { panic!("expected a watchpoint") };
+        assert_eq!((hit.pc, hit.slot, hit.old, hit.new), (assign, 1021, Value::Int(7), Value::Int(9)));
+        assert_eq!(debugger.continue_(), StopReason::WatchpointScope(local));
+        assert_eq!(debugger.watchpoints().count(), 0);
+        assert_eq!(debugger.continue_(), StopReason::Halted);
+
+        debugger.watch(1021, WatchKind::Write);
+        let StopReason::Watchpoint(hit) = debugger.reverse_continue() else { panic!("expected a watchpoint") };
+        assert_eq!((hit.pc, hit.new), (assign, Value::Int(9)));
+        assert_eq!(debugger.pc(), assign);
+    }
+
+    #[test]
+    fn test_json() {
+        let text = r#"{"a":[1,-2.5,true,null],"b":"q\"\u00e9\ud83d\ude00\n","c":{}}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(json.get("a").and_then(|a| a.as_array()).map(|a| a.len()), Some(4));
+        assert_eq!(json.get("b").and_then(Json::as_str), Some("q\"\u{e9}\u{1f600}\n"));
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+        assert_eq!(Json::object([("n", Json::from(3)), ("s", Json::from("x"))]).to_string(), r#"{"n":3,"s":"x"}"#);
+        assert!(Json::parse("[1,]").is_err());
+        assert!(Json::parse("\"\\ud800\"").is_err());
+        assert!(Json::parse(&"[".repeat(1000)).is_err());
+    }
+
+    #[test]
+    fn test_dap_session() {
+        // main.src : 1 f(); 2 halt. 10 fn f() { 11 let x = 5; 12 }
+        let mut instrs = InstructionList::new();
+        instrs.set_location("main.src", 1, 1);
+        instrs.push_instruction(Instruction::Call);
+        let call = instrs.len();
+        instrs.push_i32_operand(0);
+        instrs.set_location("main.src", 2, 1);
+        instrs.push_instruction(Instruction::Hlt);
+        instrs.set_i32_operand(instrs.len() as i32, call);
+        instrs.add_function("f");
+        instrs.set_location("main.src", 10, 1);
+        instrs.push_instruction(Instruction::Enter);
+        instrs.push_i32_operand(1);
+        instrs.set_location("main.src", 11, 5);
+        instrs.push_instruction(Instruction::Push);
+        instrs.push_i32_operand(5);
+        instrs.push_instruction(Instruction::StoreRelative);
+        instrs.push_i32_operand(0);
+        instrs.set_location("main.src", 12, 1);
+        instrs.push_instruction(Instruction::Leave);
+        instrs.push_instruction(Instruction::Ret);
+        let path = std::env::temp_dir().join(format!("bytecode-dap-{}.bc", std::process::id()));
+        std::fs::write(&path, instrs.into_module().to_bytes()).unwrap();
+
+        let mut session = Session::new();
+        let mut seq = 0;
+        let mut request = |session: &mut Session, command: &str, arguments: Json| {
+            seq += 1;
+            let mut messages = session.handle(&Json::object([
+                ("seq", Json::from(seq)), ("type", Json::from("request")), ("command", Json::from(command)), ("arguments", arguments),
+            ]));
+            while session.is_running() {
+                messages.extend(session.poll());
+            }
+            assert_eq!(messages[0].get("success"), Some(&Json::Bool(true)), "{}", messages[0]);
+            messages
+        };
+        let stopped = |messages: &[Json]| messages.iter()
+            .find(|message| message.get("event").and_then(Json::as_str) == Some("stopped"))
+            .and_then(|message| message.get("body")?.get("reason")?.as_str().map(str::to_string));
+        let body = |messages: &[Json]| messages[0].get("body").cloned().unwrap_or(Json::Null);
+
+        let capabilities = body(&request(&mut session, "initialize", Json::Null));
+        assert_eq!(capabilities.get("supportsStepBack"), Some(&Json::Bool(true)));
+        let launched = request(&mut session, "launch", Json::object([("program", Json::from(path.to_string_lossy().into_owned()))]));
+        assert_eq!(launched[1].get("event").and_then(Json::as_str), Some("initialized"));
+        let breakpoints = body(&request(&mut session, "setBreakpoints", Json::object([
+            ("source", Json::object([("path", Json::from("/src/main.src"))])),
+            ("breakpoints", Json::from(vec![Json::object([("line", Json::from(11))])])),
+        ])));
+        let breakpoint = &breakpoints.get("breakpoints").and_then(Json::as_array).unwrap()[0];
+        assert_eq!(breakpoint.get("verified"), Some(&Json::Bool(true)));
+
+        assert_eq!(stopped(&request(&mut session, "configurationDone", Json::Null)).as_deref(), Some("breakpoint"));
+        let trace = body(&request(&mut session, "stackTrace", Json::object([("threadId", Json::from(1))])));
+        let frames = trace.get("stackFrames").and_then(Json::as_array).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].get("name").and_then(Json::as_str), Some("f"));
+        assert_eq!(frames[0].get("line").and_then(Json::as_i64), Some(11));
+        assert_eq!(frames[1].get("line").and_then(Json::as_i64), Some(1));
+
+        assert_eq!(stopped(&request(&mut session, "next", Json::Null)).as_deref(), Some("step"));
+        let locals = body(&request(&mut session, "variables", Json::object([("variablesReference", Json::from(3))])));
+        let local = &locals.get("variables").and_then(Json::as_array).unwrap()[0];
+        assert_eq!((local.get("name").and_then(Json::as_str), local.get("value").and_then(Json::as_str)), (Some("[fp+0]"), Some("5")));
+        let result = body(&request(&mut session, "evaluate", Json::object([("expression", Json::from("[fp + 0] - 2"))])));
+        assert_eq!(result.get("result").and_then(Json::as_str), Some("3"));
+        let result = body(&request(&mut session, "evaluate", Json::object([("expression", Json::from("frame_ptr"))])));
+        assert_eq!(result.get("result").and_then(Json::as_str), Some("1021"));
+        let vm = session.debugger().unwrap().interpreter();
+        for expression in ["[".repeat(100_000), "-".repeat(100_000)] {
+            assert_eq!(crate::dap::evaluate(vm, 0, &expression), Err("expression nested too deeply".to_string()));
+        }
+        assert_eq!(crate::dap::evaluate(vm, 0, "--[1023]"), Ok("5".to_string()));
+
+        assert_eq!(stopped(&request(&mut session, "stepOut", Json::Null)).as_deref(), Some("step"));
+        assert_eq!(session.debugger().unwrap().interpreter().frame_ptr(), 0);
+        assert_eq!(stopped(&request(&mut session, "stepBack", Json::Null)).as_deref(), Some("step"));
+        assert_eq!(session.debugger().unwrap().interpreter().frame_ptr(), 1021);
+
+        let events: Vec<_> = request(&mut session, "continue", Json::Null).iter()
+            .filter_map(|message| message.get("event").and_then(Json::as_str).map(str::to_string))
+            .collect();
+        assert_eq!(events, ["exited", "terminated"]);
+        request(&mut session, "disconnect", Json::Null);
+        assert!(session.is_finished());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_gdb_stub() {
+        let mut out = Vec::new();
+        gdb::write_packet(&mut out, "a#b").unwrap();
+        assert_eq!(out, b"$a}\x03b#43");
+        let mut input: &[u8] = b"+$a}\x03b#43$OK#00\x03";
+        assert_eq!(gdb::read_input(&mut input).unwrap(), Some(Input::Ack));
+        assert_eq!(gdb::read_input(&mut input).unwrap(), Some(Input::Packet(b"a#b".to_vec())));
+        assert_eq!(gdb::read_input(&mut input).unwrap(), Some(Input::Corrupt));
+        assert_eq!(gdb::read_input(&mut input).unwrap(), Some(Input::Interrupt));
+        assert_eq!(gdb::read_input(&mut input).unwrap(), None);
+
+        let (module, head) = sum_loop();
+        let mut stub = GdbStub::new(Debugger::new(Interpreter::from_module(module)));
+        let send = |stub: &mut GdbStub, packet: &str| stub.handle(packet.as_bytes());
+        assert!(send(&mut stub, "qSupported:xmlRegisters=i386").unwrap().contains("qXfer:features:read+"));
+        let xml = send(&mut stub, "qXfer:features:read:target.xml:0,ffff").unwrap();
+        assert_eq!(xml, format!("l{}", gdb::target_xml()));
+        assert!(xml.contains(r#"<field name="div_by_zero" start="8" end="8"/>"#));
+        assert_eq!(send(&mut stub, "g").unwrap(), "00000000ff0300000000000000000000");
+
+        assert_eq!(send(&mut stub, &format!("Z0,{:x},1", head)).unwrap(), "OK");
+        assert_eq!(send(&mut stub, "c").unwrap(), "T05swbreak:;");
+        assert_eq!(send(&mut stub, "p0").unwrap(), format!("{:02x}000000", head));
+        assert_eq!(send(&mut stub, "mffc,4").unwrap(), "00000000");
+        assert_eq!(send(&mut stub, "m40000008,4").unwrap(), "00000000");
+        assert_eq!(send(&mut stub, "m40000010,4").unwrap(), "E01");
+        assert_eq!(send(&mut stub, "Mffc,4:2a000000").unwrap(), "OK");
+        assert_eq!(stub.debugger().interpreter().stack_value(1023).unwrap(), Value::Int(42));
+
+        assert_eq!(send(&mut stub, &format!("z0,{:x},1", head)).unwrap(), "OK");
+        assert_eq!(send(&mut stub, "Z2,ff8,4").unwrap(), "OK");
+        assert_eq!(send(&mut stub, "vCont;c").unwrap(), "T05watch:ff8;");
+        assert_eq!(send(&mut stub, "bs").unwrap(), "S05");
+        assert_eq!(send(&mut stub, "z2,ff8,4").unwrap(), "OK");
+        assert_eq!(send(&mut stub, "c").unwrap(), "W00");
+        assert_eq!(stub.debugger().interpreter().peek(), 42 + 55);
+        assert_eq!(send(&mut stub, "?").unwrap(), "W00");
+        assert_eq!(send(&mut stub, "k"), None);
+        assert!(stub.is_finished());
+    }
+
+    #[test]
+    fn test_assembler() {
+        let mut asm = Assembler::new();
+        for line in [
+            "Push 10 ; counter",
+            "top: Push -1",
+            "I32Add",
+            "Store 1023",
+            "Load 1023",
+            "Jnz top",
+            r#"PushConst "a;b, \"c\"""#,
+            "compilercall print_str",
+            "TailCall 0x10, 2, 1",
+        ] {
+            asm.assemble_line(line).unwrap();
+        }
+        assert_eq!(asm.label("top"), Some(5));
+        assert_eq!(asm.module().constants, [Constant::String("a;b, \"c\"".to_string())]);
+
+        let module = asm.module().clone();
+        assert_eq!(&module.code[21..26], [u8::from(Instruction::Jnz), 5, 0, 0, 0]);
+        assert_eq!(&module.code[31..36], [u8::from(Instruction::CompilerCall), u8::from(CompilerCall::PrintStr), 0, 0, 0]);
+
+        // Disassembly reads back in as the same code.
+        let mut again = Assembler::new();
+        for line in module.disassembly().to_string().lines() {
+            again.assemble_line(line).unwrap();
+        }
+        assert_eq!(again.module().code, module.code);
+
+        assert_eq!(asm.assemble_line("Bogus"), Err(AsmError::UnknownInstruction("Bogus".to_string())));
+        assert_eq!(asm.assemble_line("Push 1, 2"), Err(AsmError::OperandCount { ins: Instruction::Push, expected: 1, found: 2 }));
+        assert_eq!(asm.assemble_line("PushReg 256"), Err(AsmError::OperandRange { operand: "256".to_string(), width: 1 }));
+        assert_eq!(asm.assemble_line("Jmp later"), Err(AsmError::UnknownLabel("later".to_string())));
+        assert_eq!(asm.assemble_line("top: Nop"), Err(AsmError::DuplicateLabel("top".to_string())));
+        assert_eq!(asm.module(), &module);
+        assert_eq!(asm.assemble_line("  ; nothing"), Ok(43..43));
+
+        // Appended code can be swapped in under a running interpreter, other code cannot.
+        let mut asm = Assembler::new();
+        asm.assemble_line("Push 2").unwrap();
+        let mut int = Interpreter::with_program(Arc::new(Program::new(asm.module().clone()).unwrap()));
+        int.run().unwrap();
+        asm.assemble_line("Push 3").unwrap();
+        asm.assemble_line("I32Add").unwrap();
+        int.set_program(Arc::new(Program::new(asm.module().clone()).unwrap())).unwrap();
+        int.run().unwrap();
+        assert_eq!(int.peek(), 5);
+        let mut other = Assembler::new();
+        other.assemble_line("Push 4").unwrap();
+        assert!(int.set_program(Arc::new(Program::new(other.module().clone()).unwrap())).is_err());
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::fmt::Display;
+
+use crate::debug_info::DebugInfo;
+use crate::interpreter::Disassembly;
+
+const MODULE_MAGIC: &[u8; 4] = b"BCM\0";
+pub const MODULE_VERSION: u16 = 1;
+
+const SECTION_CODE: u8 = 1;
+const SECTION_CONSTANTS: u8 = 2;
+const SECTION_FUNCTIONS: u8 = 3;
+const SECTION_DEBUG_INFO: u8 = 4;
+const SECTION_HANDLERS: u8 = 5;
+
+const CONSTANT_STRING: u8 = 0;
+const CONSTANT_BYTES: u8 = 1;
+const CONSTANT_I64: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Constant {
+    String(String),
+    Bytes(Vec<u8>),
+    I64(i64),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ModuleError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    UnknownSection(u8),
+    UnknownConstant(u8),
+    InvalidUtf8,
+    InvalidDebugInfo,
+}
+
+impl Display for ModuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModuleError::BadMagic => write!(f, "not a bytecode module"),
+            ModuleError::UnsupportedVersion(version) => write!(f, "unsupported module version : {}", version),
+            ModuleError::Truncated => write!(f, "module is truncated"),
+            ModuleError::UnknownSection(id) => write!(f, "unknown section id : {}", id),
+            ModuleError::UnknownConstant(tag) => write!(f, "unknown constant tag : {}", tag),
+            ModuleError::InvalidUtf8 => write!(f, "string is not valid utf-8"),
+            ModuleError::InvalidDebugInfo => write!(f, "debug info offset or position out of range"),
+        }
+    }
+}
+
+impl std::error::Error for ModuleError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub offset: u32,
+}
+
+// Code in `start..end` that throws continues at `target` with the stack cut back
+// to `depth` slots below the frame pointer and the thrown value pushed on top.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Handler {
+    pub start: u32,
+    pub end: u32,
+    pub target: u32,
+    pub depth: u32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Module {
+    pub code: Vec<u8>,
+    pub constants: Vec<Constant>,
+    pub functions: Vec<Function>,
+    pub debug_info: DebugInfo,
+    pub handlers: Vec<Handler>,
+}
+
+impl Module {
+    pub fn new(code: Vec<u8>) -> Module {
+        Module { code, ..Default::default() }
+    }
+
+    pub fn disassembly(&self) -> Disassembly<'_> {
+        Disassembly { code: &self.code, debug_info: Some(&self.debug_info) }
+    }
+
+    // Layout: magic, version, then a list of (section id : u8, length : u32, payload) entries.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MODULE_MAGIC);
+        bytes.extend_from_slice(&MODULE_VERSION.to_le_bytes());
+
+        write_section(&mut bytes, SECTION_CODE, &self.code);
+
+        let mut constants = Vec::new();
+        write_u32(&mut constants, self.constants.len() as u32);
+        for constant in &self.constants {
+            match constant {
+                Constant::String(str) => {
+                    constants.push(CONSTANT_STRING);
+                    write_blob(&mut constants, str.as_bytes());
+                }
+                Constant::Bytes(blob) => {
+                    constants.push(CONSTANT_BYTES);
+                    write_blob(&mut constants, blob);
+                }
+                Constant::I64(val) => {
+                    constants.push(CONSTANT_I64);
+                    constants.extend_from_slice(&val.to_le_bytes());
+                }
+            }
+        }
+        write_section(&mut bytes, SECTION_CONSTANTS, &constants);
+
+        if !self.functions.is_empty() {
+            let mut functions = Vec::new();
+            write_u32(&mut functions, self.functions.len() as u32);
+            for function in &self.functions {
+                write_u32(&mut functions, function.offset);
+                write_blob(&mut functions, function.name.as_bytes());
+            }
+            write_section(&mut bytes, SECTION_FUNCTIONS, &functions);
+        }
+
+        if !self.handlers.is_empty() {
+            let mut handlers = Vec::new();
+            write_u32(&mut handlers, self.handlers.len() as u32);
+            for handler in &self.handlers {
+                write_u32(&mut handlers, handler.start);
+                write_u32(&mut handlers, handler.end);
+                write_u32(&mut handlers, handler.target);
+                write_u32(&mut handlers, handler.depth);
+            }
+            write_section(&mut bytes, SECTION_HANDLERS, &handlers);
+        }
+
+        if !self.debug_info.is_empty() {
+            write_section(&mut bytes, SECTION_DEBUG_INFO, &self.debug_info.to_bytes());
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Module, ModuleError> {
+        let mut reader = Reader { bytes, index: 0 };
+        if reader.take(4)? != MODULE_MAGIC {
+            return Err(ModuleError::BadMagic);
+        }
+        let version = u16::from_le_bytes([reader.u8()?, reader.u8()?]);
+        if version != MODULE_VERSION {
+            return Err(ModuleError::UnsupportedVersion(version));
+        }
+
+        let mut module = Module::default();
+        while !reader.is_empty() {
+            let id = reader.u8()?;
+            let payload = reader.blob()?;
+            let mut section = Reader { bytes: payload, index: 0 };
+            match id {
+                SECTION_CODE => module.code = payload.to_vec(),
+                SECTION_CONSTANTS => {
+                    let count = section.u32()?;
+                    for _ in 0..count {
+                        let constant = match section.u8()? {
+                            CONSTANT_STRING => Constant::String(
+                                String::from_utf8(section.blob()?.to_vec()).map_err(|_| ModuleError::InvalidUtf8)?
+                            ),
+                            CONSTANT_BYTES => Constant::Bytes(section.blob()?.to_vec()),
+                            CONSTANT_I64 => {
+                                let mut val = [0; 8];
+                                val.copy_from_slice(section.take(8)?);
+                                Constant::I64(i64::from_le_bytes(val))
+                            }
+                            tag => return Err(ModuleError::UnknownConstant(tag)),
+                        };
+                        module.constants.push(constant);
+                    }
+                }
+                SECTION_FUNCTIONS => {
+                    let count = section.u32()?;
+                    for _ in 0..count {
+                        let offset = section.u32()?;
+                        let name = String::from_utf8(section.blob()?.to_vec()).map_err(|_| ModuleError::InvalidUtf8)?;
+                        module.functions.push(Function { name, offset });
+                    }
+                }
+                SECTION_HANDLERS => {
+                    let count = section.u32()?;
+                    for _ in 0..count {
+                        module.handlers.push(Handler {
+                            start: section.u32()?,
+                            end: section.u32()?,
+                            target: section.u32()?,
+                            depth: section.u32()?,
+                        });
+                    }
+                }
+                SECTION_DEBUG_INFO => module.debug_info = DebugInfo::from_bytes(payload)?,
+                id => return Err(ModuleError::UnknownSection(id)),
+            }
+        }
+
+        Ok(module)
+    }
+}
+
+fn write_u32(bytes: &mut Vec<u8>, val: u32) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn write_blob(bytes: &mut Vec<u8>, blob: &[u8]) {
+    write_u32(bytes, blob.len() as u32);
+    bytes.extend_from_slice(blob);
+}
+
+fn write_section(bytes: &mut Vec<u8>, id: u8, payload: &[u8]) {
+    bytes.push(id);
+    write_blob(bytes, payload);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool {
+        self.index >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ModuleError> {
+        let end = self.index.checked_add(len).ok_or(ModuleError::Truncated)?;
+        let slice = self.bytes.get(self.index..end).ok_or(ModuleError::Truncated)?;
+        self.index = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, ModuleError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ModuleError> {
+        let mut val = [0; 4];
+        val.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(val))
+    }
+
+    fn blob(&mut self) -> Result<&'a [u8], ModuleError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+}
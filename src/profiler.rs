@@ -0,0 +1,279 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::hook::{ExecutionHook, FrameEvent, FrameTracker};
+use crate::interpreter::{Instruction, Interpreter};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub self_instructions: u64,
+    pub total_instructions: u64,
+    pub self_time: Duration,
+    pub total_time: Duration,
+}
+
+#[derive(Clone)]
+struct Frame {
+    function: usize,
+    // The call stack up to and including this frame, see `Profiler::stacks`.
+    stack: usize,
+    entered: Instant,
+    child_time: Duration,
+    instructions: u64,
+}
+
+// Counts every executed instruction by op code and by offset, and follows the
+// `Call`/`Ret` frames to attribute instructions and wall time to functions. A
+// recursive function counts towards the totals once per active frame, frames
+// are followed across coroutines as `FrameTracker` describes. Functions are
+// named after the `Module` function table, or `fn@offset` when not listed.
+pub struct Profiler {
+    opcodes: Vec<u64>,
+    offsets: Vec<u64>,
+    functions: Vec<FunctionProfile>,
+    function_ids: HashMap<String, usize>,
+    frames: Vec<Frame>,
+    tracker: FrameTracker,
+    // Every distinct call stack interned as its caller's stack and the function
+    // called, so sampling an instruction is a single counter bump.
+    stacks: Vec<StackNode>,
+    stack_ids: HashMap<(Option<usize>, usize), usize>,
+    last: Option<Instant>,
+}
+
+struct StackNode {
+    parent: Option<usize>,
+    function: usize,
+    instructions: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            opcodes: vec![0; 256],
+            offsets: Vec::new(),
+            functions: Vec::new(),
+            function_ids: HashMap::new(),
+            frames: Vec::new(),
+            tracker: FrameTracker::new(),
+            stacks: Vec::new(),
+            stack_ids: HashMap::new(),
+            last: None,
+        }
+    }
+
+    // Executed op codes, most frequent first.
+    pub fn opcode_counts(&self) -> Vec<(Instruction, u64)> {
+        let mut counts: Vec<(Instruction, u64)> = self.opcodes.iter().enumerate()
+            .filter(|(_, count)| **count != 0)
+            .map(|(op, count)| (Instruction::from(op as u8), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then((a.0 as u8).cmp(&(b.0 as u8))));
+        counts
+    }
+
+    pub fn offset_count(&self, offset: usize) -> u64 {
+        self.offsets.get(offset).copied().unwrap_or(0)
+    }
+
+    // Executed offsets, most frequent first.
+    pub fn offset_counts(&self) -> Vec<(usize, u64)> {
+        let mut counts: Vec<(usize, u64)> = self.offsets.iter().copied().enumerate()
+            .filter(|(_, count)| *count != 0)
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        counts
+    }
+
+    // Frames still active are closed as of the last executed instruction. Sorted by
+    // self instructions, most first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions = self.functions.clone();
+        let mut frames = self.frames.clone();
+        let now = self.last.unwrap_or_else(Instant::now);
+        while !frames.is_empty() {
+            pop_frame(&mut functions, &mut frames, now);
+        }
+        functions.sort_by(|a, b| b.self_instructions.cmp(&a.self_instructions).then_with(|| a.name.cmp(&b.name)));
+        functions
+    }
+
+    pub fn function(&self, name: &str) -> Option<FunctionProfile> {
+        self.functions().into_iter().find(|function| function.name == name)
+    }
+
+    pub fn report(&self) -> Report<'_> {
+        Report { profiler: self }
+    }
+
+    // One `outer;inner count` line per distinct call stack, counting instructions,
+    // as read by flamegraph.pl and inferno.
+    pub fn folded(&self) -> String {
+        let mut lines: Vec<String> = self.stacks.iter().filter(|node| node.instructions != 0).map(|node| {
+            let mut names = vec![folded_name(&self.functions[node.function].name)];
+            let mut parent = node.parent;
+            while let Some(caller) = parent {
+                names.push(folded_name(&self.functions[self.stacks[caller].function].name));
+                parent = self.stacks[caller].parent;
+            }
+            names.reverse();
+            format!("{} {}", names.join(";"), node.instructions)
+        }).collect();
+        lines.sort();
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    fn function_id(&mut self, name: String) -> usize {
+        if let Some(id) = self.function_ids.get(&name) {
+            return *id;
+        }
+        self.functions.push(FunctionProfile { name: name.clone(), ..Default::default() });
+        self.function_ids.insert(name, self.functions.len() - 1);
+        self.functions.len() - 1
+    }
+
+    fn push_frame(&mut self, vm: &Interpreter, pc: usize) {
+        let name = vm.function_name(pc).unwrap_or_else(|| format!("fn@{}", pc));
+        let function = self.function_id(name);
+        self.functions[function].calls += 1;
+        let parent = self.frames.last().map(|frame| frame.stack);
+        let stack = match self.stack_ids.get(&(parent, function)) {
+            Some(stack) => *stack,
+            None => {
+                self.stacks.push(StackNode { parent, function, instructions: 0 });
+                self.stack_ids.insert((parent, function), self.stacks.len() - 1);
+                self.stacks.len() - 1
+            }
+        };
+        self.frames.push(Frame {
+            function,
+            stack,
+            entered: Instant::now(),
+            child_time: Duration::ZERO,
+            instructions: 0,
+        });
+    }
+
+    fn apply(&mut self, vm: &Interpreter, events: Vec<FrameEvent>) {
+        for event in events {
+            match event {
+                FrameEvent::Enter(entry) => self.push_frame(vm, entry),
+                FrameEvent::Exit => pop_frame(&mut self.functions, &mut self.frames, Instant::now()),
+            }
+        }
+    }
+}
+
+fn pop_frame(functions: &mut [FunctionProfile], frames: &mut Vec<Frame>, now: Instant) {
+    let Some(frame) = frames.pop() else {
+        return;
+    };
+    let elapsed = now.saturating_duration_since(frame.entered);
+    let function = &mut functions[frame.function];
+    function.total_time += elapsed;
+    function.self_time += elapsed.saturating_sub(frame.child_time);
+    function.total_instructions += frame.instructions;
+    if let Some(parent) = frames.last_mut() {
+        parent.child_time += elapsed;
+        parent.instructions += frame.instructions;
+    }
+}
+
+fn folded_name(name: &str) -> String {
+    name.replace([';', ' '], "_")
+}
+
+impl ExecutionHook for Profiler {
+    fn before_instruction(&mut self, vm: &Interpreter, pc: usize, ins: Instruction) {
+        let events = self.tracker.before_instruction(vm, pc, ins);
+        self.apply(vm, events);
+        self.opcodes[ins as u8 as usize] += 1;
+        if pc >= self.offsets.len() {
+            self.offsets.resize(pc + 1, 0);
+        }
+        self.offsets[pc] += 1;
+
+        let Some(frame) = self.frames.last_mut() else {
+            return;
+        };
+        frame.instructions += 1;
+        self.functions[frame.function].self_instructions += 1;
+        self.stacks[frame.stack].instructions += 1;
+    }
+
+    fn after_instruction(&mut self, vm: &Interpreter, _pc: usize, ins: Instruction) {
+        self.last = Some(Instant::now());
+        let events = self.tracker.after_instruction(vm, ins);
+        self.apply(vm, events);
+    }
+
+    fn on_call(&mut self, vm: &Interpreter, _pc: usize, to: usize) {
+        let events = self.tracker.on_call(vm, to);
+        self.apply(vm, events);
+    }
+
+    fn on_return(&mut self, vm: &Interpreter, _pc: usize, _to: usize) {
+        let events = self.tracker.on_return(vm);
+        self.apply(vm, events);
+    }
+}
+
+pub struct Report<'a> {
+    profiler: &'a Profiler,
+}
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{:>10} {:>12} {:>12} {:>12} {:>12}  function", "calls", "self", "total", "self time", "total time")?;
+        for function in self.profiler.functions() {
+            writeln!(
+                f, "{:>10} {:>12} {:>12} {:>12} {:>12}  {}",
+                function.calls, function.self_instructions, function.total_instructions,
+                format!("{:.3?}", function.self_time), format!("{:.3?}", function.total_time), function.name,
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>12}  opcode", "count")?;
+        for (ins, count) in self.profiler.opcode_counts() {
+            writeln!(f, "{:>12}  {:?}", count, ins)?;
+        }
+
+        writeln!(f)?;
+        writeln!(f, "{:>12}  offset", "count")?;
+        for (offset, count) in self.profiler.offset_counts().iter().take(20) {
+            writeln!(f, "{:>12}  {}", count, offset)?;
+        }
+        Ok(())
+    }
+}
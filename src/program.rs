@@ -0,0 +1,216 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::fmt::Display;
+
+use crate::debug_info::DebugInfo;
+use crate::interpreter::{Disassembly, Instruction, STACK_SIZE};
+use crate::module::{Constant, Function, Handler, Module};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VerifyError {
+    InvalidOpcode { offset: usize, opcode: u8 },
+    TruncatedOperand { offset: usize },
+    InvalidJump { offset: usize, target: i32 },
+    InvalidConstant { offset: usize, index: i32 },
+    InvalidRegister { offset: usize, register: u8 },
+    InvalidFunction { index: usize, offset: u32 },
+    InvalidHandler { index: usize },
+    InvalidCount { offset: usize, count: i32 },
+}
+
+impl Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::InvalidOpcode { offset, opcode } => write!(f, "invalid opcode {} at {}", opcode, offset),
+            VerifyError::TruncatedOperand { offset } => write!(f, "truncated operand for instruction at {}", offset),
+            VerifyError::InvalidJump { offset, target } => write!(f, "jump at {} to {} does not land on an instruction", offset, target),
+            VerifyError::InvalidConstant { offset, index } => write!(f, "constant {} at {} does not exist", index, offset),
+            VerifyError::InvalidRegister { offset, register } => write!(f, "invalid register {} at {}", register, offset),
+            VerifyError::InvalidFunction { index, offset } => write!(f, "function {} starts at {} which is not an instruction", index, offset),
+            VerifyError::InvalidHandler { index } => write!(f, "handler {} has an invalid range or target", index),
+            VerifyError::InvalidCount { offset, count } => write!(f, "count {} at {} does not fit on the stack", count, offset),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+// A verified, immutable code image. Every opcode decodes, every operand is in bounds
+// (counts popped off the stack fit on it) and every static jump, call, function and
+// handler lands on an instruction start (or exactly at the end of the code, which
+// reads as `Hlt`). Wrap it in an `Arc` to share one image between any number of
+// interpreters and threads. The interpreter checks all of this again at runtime, so
+// code that does not verify still traps rather than misbehaving.
+#[derive(Debug)]
+pub struct Program {
+    code: Box<[u8]>,
+    constants: Box<[Constant]>,
+    functions: Box<[Function]>,
+    debug_info: DebugInfo,
+    handlers: Box<[Handler]>,
+    hash: u64,
+}
+
+impl Program {
+    pub fn new(module: Module) -> Result<Program, VerifyError> {
+        let program = Self::unverified(module);
+        program.verify()?;
+        Ok(program)
+    }
+
+    // For interpreters built straight from code, which keep running code that does
+    // not verify and trap when they reach the fault.
+    pub(crate) fn unverified(module: Module) -> Program {
+        let hash = fnv1a(&module.to_bytes());
+        Program {
+            code: module.code.into_boxed_slice(),
+            constants: module.constants.into_boxed_slice(),
+            functions: module.functions.into_boxed_slice(),
+            debug_info: module.debug_info,
+            handlers: module.handlers.into_boxed_slice(),
+            hash,
+        }
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    pub fn functions(&self) -> &[Function] {
+        &self.functions
+    }
+
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug_info
+    }
+
+    pub fn handlers(&self) -> &[Handler] {
+        &self.handlers
+    }
+
+    // FNV-1a of the encoded module, stable across processes and builds so snapshots
+    // can tell which program they belong to.
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    pub fn disassembly(&self) -> Disassembly<'_> {
+        Disassembly { code: &self.code, debug_info: Some(&self.debug_info) }
+    }
+
+    pub fn to_module(&self) -> Module {
+        Module {
+            code: self.code.to_vec(),
+            constants: self.constants.to_vec(),
+            functions: self.functions.to_vec(),
+            debug_info: self.debug_info.clone(),
+            handlers: self.handlers.to_vec(),
+        }
+    }
+
+    fn verify(&self) -> Result<(), VerifyError> {
+        let mut starts = vec![false; self.code.len() + 1];
+        let mut jumps = Vec::new();
+
+        let mut offset = 0;
+        while offset < self.code.len() {
+            starts[offset] = true;
+            let opcode = self.code[offset];
+            let ins = Instruction::decode(opcode).ok_or(VerifyError::InvalidOpcode { offset, opcode })?;
+            let end = offset + 1 + ins.operand_len();
+            if end > self.code.len() {
+                return Err(VerifyError::TruncatedOperand { offset });
+            }
+
+            let operand = &self.code[offset + 1..end];
+            match ins {
+                Instruction::Jmp | Instruction::Jz | Instruction::Jnz |
+                Instruction::Call | Instruction::TailCall => jumps.push((offset, read_i32(operand))),
+                Instruction::PushConst => {
+                    let index = read_i32(operand);
+                    if index as u32 as usize >= self.constants.len() {
+                        return Err(VerifyError::InvalidConstant { offset, index });
+                    }
+                }
+                Instruction::NewRecord | Instruction::MakeClosure => {
+                    let count = read_i32(operand);
+                    if count < 0 || count as usize > STACK_SIZE {
+                        return Err(VerifyError::InvalidCount { offset, count });
+                    }
+                }
+                Instruction::PushReg | Instruction::PopReg if operand[0] > 3 => {
+                    return Err(VerifyError::InvalidRegister { offset, register: operand[0] });
+                }
+                _ => {}
+            }
+            offset = end;
+        }
+        starts[self.code.len()] = true;
+
+        let lands = |target: u32| starts.get(target as usize).copied().unwrap_or(false);
+
+        for (offset, target) in jumps {
+            if !lands(target as u32) {
+                return Err(VerifyError::InvalidJump { offset, target });
+            }
+        }
+        for (index, function) in self.functions.iter().enumerate() {
+            if !lands(function.offset) {
+                return Err(VerifyError::InvalidFunction { index, offset: function.offset });
+            }
+        }
+        for (index, handler) in self.handlers.iter().enumerate() {
+            if handler.start > handler.end || handler.end as usize > self.code.len() || !lands(handler.target) {
+                return Err(VerifyError::InvalidHandler { index });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<Module> for Program {
+    type Error = VerifyError;
+
+    fn try_from(module: Module) -> Result<Self, Self::Error> {
+        Program::new(module)
+    }
+}
+
+fn read_i32(bytes: &[u8]) -> i32 {
+    i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
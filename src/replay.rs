@@ -0,0 +1,204 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::fmt::Display;
+use std::path::Path;
+
+use crate::gc::Value;
+use crate::snapshot::{Reader, SnapshotError, Writer};
+
+const LOG_MAGIC: &[u8; 4] = b"BCR\0";
+pub const LOG_VERSION: u16 = 1;
+
+// Everything that reaches a run from outside the VM, in the order it happened.
+// Replaying feeds the logged `Receive`, `HostValue` and `ChannelReady` values
+// back instead of reading them again; the other events only check that the run
+// still takes the same path.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    Receive { pc: u32, value: Option<Value> },
+    HostValue(Value),
+    HostCall { pc: u32, call: i32 },
+    Schedule { task: u32 },
+    ChannelReady { channel: Value, ready: bool },
+}
+
+impl Event {
+    // Whether `logged` is this event, not counting the values a replay feeds back.
+    pub(crate) fn same_site(&self, logged: &Event) -> bool {
+        match (self, logged) {
+            (Event::Receive { pc, .. }, Event::Receive { pc: logged, .. }) => pc == logged,
+            (Event::HostValue(_), Event::HostValue(_)) => true,
+            (Event::ChannelReady { channel, .. }, Event::ChannelReady { channel: logged, .. }) => channel == logged,
+            (event, logged) => event == logged,
+        }
+    }
+
+    fn encode(&self, out: &mut Writer) {
+        match self {
+            Event::Receive { pc, value } => {
+                out.u8(0);
+                out.u32(*pc);
+                match value {
+                    Some(val) => {
+                        out.bool(true);
+                        out.value(*val);
+                    }
+                    None => out.bool(false),
+                }
+            }
+            Event::HostValue(val) => {
+                out.u8(1);
+                out.value(*val);
+            }
+            Event::HostCall { pc, call } => {
+                out.u8(2);
+                out.u32(*pc);
+                out.i32(*call);
+            }
+            Event::Schedule { task } => {
+                out.u8(3);
+                out.u32(*task);
+            }
+            Event::ChannelReady { channel, ready } => {
+                out.u8(4);
+                out.value(*channel);
+                out.bool(*ready);
+            }
+        }
+    }
+
+    fn decode(input: &mut Reader) -> Result<Event, SnapshotError> {
+        Ok(match input.u8()? {
+            0 => Event::Receive { pc: input.u32()?, value: if input.bool()? { Some(input.value()?) } else { None } },
+            1 => Event::HostValue(input.value()?),
+            2 => Event::HostCall { pc: input.u32()?, call: input.i32()? },
+            3 => Event::Schedule { task: input.u32()? },
+            4 => Event::ChannelReady { channel: input.value()?, ready: input.bool()? },
+            _ => return Err(SnapshotError::Corrupt("invalid event tag")),
+        })
+    }
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Receive { pc, value: Some(val) } => write!(f, "receive of {} at {}", val, pc),
+            Event::Receive { pc, value: None } => write!(f, "empty receive at {}", pc),
+            Event::HostValue(val) => write!(f, "host value {}", val),
+            Event::HostCall { pc, call } => write!(f, "host call {} at {}", call, pc),
+            Event::Schedule { task } => write!(f, "slice of task {}", task),
+            Event::ChannelReady { channel, ready } => write!(f, "channel {} ready : {}", channel, ready),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReplayError {
+    Malformed(SnapshotError),
+    ProgramMismatch { expected: u64, found: u64 },
+}
+
+impl Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Malformed(error) => write!(f, "malformed execution log : {}", error),
+            ReplayError::ProgramMismatch { expected, found } => {
+                write!(f, "log was recorded with program {:016x} but replayed with {:016x}", found, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<SnapshotError> for ReplayError {
+    fn from(error: SnapshotError) -> Self {
+        ReplayError::Malformed(error)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecutionLog {
+    program_hash: u64,
+    events: Vec<Event>,
+}
+
+impl ExecutionLog {
+    pub(crate) fn new(program_hash: u64) -> ExecutionLog {
+        ExecutionLog { program_hash, events: Vec::new() }
+    }
+
+    pub fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub(crate) fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.events.truncate(len);
+    }
+
+    // Layout: magic, version, program hash, then the events as encoded by `Event`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Writer::encode(|out| {
+            out.bytes(LOG_MAGIC);
+            out.bytes(&LOG_VERSION.to_le_bytes());
+            out.u64(self.program_hash);
+            out.usize(self.events.len());
+            for event in &self.events {
+                event.encode(out);
+            }
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ExecutionLog, ReplayError> {
+        let mut input = Reader::new(bytes);
+        if input.take(4)? != LOG_MAGIC {
+            return Err(SnapshotError::BadMagic.into());
+        }
+        let version = u16::from_le_bytes([input.u8()?, input.u8()?]);
+        if version != LOG_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version).into());
+        }
+        let program_hash = input.u64()?;
+        let events = (0..input.len(2)?).map(|_| Event::decode(&mut input)).collect::<Result<_, _>>()?;
+        if !input.is_empty() {
+            return Err(SnapshotError::Corrupt("trailing bytes").into());
+        }
+        Ok(ExecutionLog { program_hash, events })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<ExecutionLog> {
+        let bytes = std::fs::read(path)?;
+        ExecutionLog::from_bytes(&bytes).map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))
+    }
+}
@@ -0,0 +1,234 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::error::{Trap, VmError};
+use crate::gc::Value;
+use crate::interpreter::{Interpreter, TaskExit, TaskRequest};
+use crate::module::Module;
+use crate::program::Program;
+use crate::replay::Event;
+
+pub type TaskId = usize;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TaskState {
+    Ready,
+    Sleeping(u64),
+    Joining(TaskId),
+    Receiving(Value),
+    Finished(Value),
+    Failed(Trap),
+}
+
+struct Task {
+    coroutine: Value,
+    state: TaskState,
+    resume_with: Option<Value>,
+}
+
+// Runs many tasks on one interpreter, so they share its code, heap and objects while
+// each keeps its own stack and registers. Tasks run round-robin in spawn order for at
+// most `quantum` instructions at a time, which keeps every run deterministic.
+pub struct Scheduler {
+    interpreter: Interpreter,
+    tasks: Vec<Task>,
+    ready: VecDeque<TaskId>,
+    quantum: usize,
+    tick: u64,
+}
+
+impl Scheduler {
+    pub fn new(module: Module, quantum: usize) -> Scheduler {
+        Self::with_interpreter(Interpreter::from_module(module), quantum)
+    }
+
+    pub fn with_program(program: Arc<Program>, quantum: usize) -> Scheduler {
+        Self::with_interpreter(Interpreter::with_program(program), quantum)
+    }
+
+    fn with_interpreter(interpreter: Interpreter, quantum: usize) -> Scheduler {
+        Scheduler {
+            interpreter,
+            tasks: Vec::new(),
+            ready: VecDeque::new(),
+            quantum: quantum.max(1),
+            tick: 0,
+        }
+    }
+
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
+
+    pub fn interpreter_mut(&mut self) -> &mut Interpreter {
+        &mut self.interpreter
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn spawn(&mut self, function: u32) -> Result<TaskId, VmError> {
+        self.spawn_task(function, None)
+    }
+
+    // Starts the task with `arg` on top of its stack, e.g. a channel created by the host.
+    // `arg` is an input like any other, a recording logs it and a replay hands it back.
+    pub fn spawn_with(&mut self, function: u32, arg: Value) -> Result<TaskId, VmError> {
+        let arg = self.interpreter.host_value(arg)?;
+        self.spawn_task(function, Some(arg))
+    }
+
+    fn spawn_task(&mut self, function: u32, arg: Option<Value>) -> Result<TaskId, VmError> {
+        let coroutine = self.interpreter.create_coroutine(function)?;
+        self.interpreter.pin(coroutine);
+        self.tasks.push(Task { coroutine, state: TaskState::Ready, resume_with: arg });
+        self.ready.push_back(self.tasks.len() - 1);
+        Ok(self.tasks.len() - 1)
+    }
+
+    pub fn state(&self, task: TaskId) -> Option<&TaskState> {
+        self.tasks.get(task).map(|task| &task.state)
+    }
+
+    // Runs until every task has finished, failed or waits on a channel only the host
+    // can fill, in which case `run` can be called again after sending. Tasks left
+    // waiting on each other with nothing else to run are reported as a deadlock.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        loop {
+            if self.ready.is_empty() && !self.wake_blocked()? {
+                let receiving = self.tasks.iter().any(|task| matches!(task.state, TaskState::Receiving(_)));
+                let joining = self.tasks.iter().any(|task| matches!(task.state, TaskState::Joining(_)));
+                return if joining && !receiving { Err(VmError::Deadlock) } else { Ok(()) };
+            }
+            while let Some(id) = self.ready.pop_front() {
+                self.interpreter.journal(Event::Schedule { task: id as u32 })?;
+                self.run_slice(id);
+                self.tick += 1;
+                self.wake_blocked()?;
+            }
+        }
+    }
+
+    fn run_slice(&mut self, id: TaskId) {
+        let coroutine = self.tasks[id].coroutine;
+        let val = self.tasks[id].resume_with.take();
+        let exit = match self.interpreter.resume_task(coroutine, val, self.quantum) {
+            Ok(exit) => exit,
+            Err(trap) => return self.finish(id, TaskState::Failed(trap)),
+        };
+
+        match exit {
+            TaskExit::OutOfFuel => self.ready.push_back(id),
+            TaskExit::Yielded(_) => {
+                self.tasks[id].resume_with = Some(Value::Int(0));
+                self.ready.push_back(id);
+            }
+            TaskExit::Finished(val) => self.finish(id, TaskState::Finished(val)),
+            TaskExit::Request(TaskRequest::Spawn(function)) => {
+                let spawned = match self.spawn(function as u32) {
+                    Ok(spawned) => spawned as i32,
+                    Err(error) => return self.fail(id, error),
+                };
+                self.tasks[id].resume_with = Some(Value::Int(spawned));
+                self.ready.push_back(id);
+            }
+            TaskExit::Request(TaskRequest::Join(target)) => {
+                match self.tasks.get(target as u32 as usize).map(|task| &task.state) {
+                    None => self.fail(id, VmError::InvalidTask(target)),
+                    Some(TaskState::Failed(_)) => self.fail(id, VmError::JoinFailed(target)),
+                    Some(TaskState::Finished(val)) => {
+                        self.tasks[id].resume_with = Some(*val);
+                        self.ready.push_back(id);
+                    }
+                    Some(_) => self.tasks[id].state = TaskState::Joining(target as usize),
+                }
+            }
+            TaskExit::Request(TaskRequest::Sleep(ticks)) => {
+                self.tasks[id].state = TaskState::Sleeping(self.tick + ticks.max(0) as u64);
+            }
+            TaskExit::Request(TaskRequest::Recv(channel)) => {
+                self.tasks[id].state = TaskState::Receiving(channel);
+            }
+        }
+    }
+
+    fn fail(&mut self, id: TaskId, error: VmError) {
+        let trap = Trap { error, backtrace: Default::default() };
+        self.finish(id, TaskState::Failed(trap));
+    }
+
+    fn finish(&mut self, id: TaskId, state: TaskState) {
+        self.interpreter.unpin(self.tasks[id].coroutine);
+        let result = match &state {
+            TaskState::Finished(val) => Ok(*val),
+            _ => Err(VmError::JoinFailed(id as i32)),
+        };
+        self.tasks[id].state = state;
+
+        for joiner in 0..self.tasks.len() {
+            if self.tasks[joiner].state != TaskState::Joining(id) {
+                continue;
+            }
+            match result {
+                Ok(val) => {
+                    self.tasks[joiner].state = TaskState::Ready;
+                    self.tasks[joiner].resume_with = Some(val);
+                    self.ready.push_back(joiner);
+                }
+                Err(ref error) => self.fail(joiner, error.clone()),
+            }
+        }
+    }
+
+    // Wakes every task whose sleep is over or whose channel has data. When nothing else
+    // can run the clock jumps straight to the earliest wake-up. Returns whether anything
+    // was woken. Channel checks go through the interpreter so they can be replayed.
+    fn wake_blocked(&mut self) -> Result<bool, VmError> {
+        if self.ready.is_empty() {
+            let earliest = self.tasks.iter().filter_map(|task| match task.state {
+                TaskState::Sleeping(until) => Some(until),
+                _ => None,
+            }).min();
+            if let Some(earliest) = earliest {
+                self.tick = self.tick.max(earliest);
+            }
+        }
+
+        let mut woken = false;
+        for id in 0..self.tasks.len() {
+            let wake = match self.tasks[id].state {
+                TaskState::Sleeping(until) => until <= self.tick,
+                TaskState::Receiving(channel) => self.interpreter.channel_ready(channel)?,
+                _ => false,
+            };
+            if wake {
+                self.tasks[id].state = TaskState::Ready;
+                self.ready.push_back(id);
+                woken = true;
+            }
+        }
+        Ok(woken)
+    }
+}
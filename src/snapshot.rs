@@ -0,0 +1,252 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::fmt::Display;
+
+use crate::gc::Value;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"BCS\0";
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    ProgramMismatch { expected: u64, found: u64 },
+    Corrupt(&'static str),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not an interpreter snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version : {}", version),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::ProgramMismatch { expected, found } => {
+                write!(f, "snapshot was taken from program {:016x} but restored into {:016x}", found, expected)
+            }
+            SnapshotError::Corrupt(what) => write!(f, "snapshot is corrupt : {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// The encoded state of a suspended interpreter. Layout: magic, version, the hash of
+// the program it was taken from, then the interpreter state as written by
+// `Interpreter::snapshot`. Only the header is checked until it is restored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    bytes: Vec<u8>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(program_hash: u64, write: impl FnOnce(&mut Writer)) -> Snapshot {
+        let bytes = Writer::encode(|out| {
+            out.bytes(SNAPSHOT_MAGIC);
+            out.bytes(&SNAPSHOT_VERSION.to_le_bytes());
+            out.u64(program_hash);
+            write(out);
+        });
+        Snapshot { bytes }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Snapshot, SnapshotError> {
+        let snapshot = Snapshot { bytes };
+        snapshot.reader()?;
+        Ok(snapshot)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn program_hash(&self) -> u64 {
+        let mut hash = [0; 8];
+        hash.copy_from_slice(&self.bytes[6..14]);
+        u64::from_le_bytes(hash)
+    }
+
+    // Returns a reader positioned after the header.
+    pub(crate) fn reader(&self) -> Result<Reader<'_>, SnapshotError> {
+        let mut reader = Reader::new(&self.bytes);
+        if reader.take(4)? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = u16::from_le_bytes([reader.u8()?, reader.u8()?]);
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        reader.u64()?;
+        Ok(reader)
+    }
+}
+
+pub(crate) struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn encode(write: impl FnOnce(&mut Writer)) -> Vec<u8> {
+        let mut writer = Writer { bytes: Vec::new() };
+        write(&mut writer);
+        writer.bytes
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.u8(val as u8);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, val: i32) {
+        self.u32(val as u32);
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, val: usize) {
+        self.u64(val as u64);
+    }
+
+    pub fn blob(&mut self, blob: &[u8]) {
+        self.usize(blob.len());
+        self.bytes.extend_from_slice(blob);
+    }
+
+    pub fn value(&mut self, val: Value) {
+        match val {
+            Value::Int(val) => {
+                self.u8(0);
+                self.i32(val);
+            }
+            Value::Ref(index) => {
+                self.u8(1);
+                self.u32(index);
+            }
+        }
+    }
+
+    pub fn values<'a>(&mut self, values: impl ExactSizeIterator<Item = &'a Value>) {
+        self.usize(values.len());
+        for val in values {
+            self.value(*val);
+        }
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    index: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, index: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index >= self.bytes.len()
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self.index.checked_add(len).ok_or(SnapshotError::Truncated)?;
+        let slice = self.bytes.get(self.index..end).ok_or(SnapshotError::Truncated)?;
+        self.index = end;
+        Ok(slice)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupt("invalid bool")),
+        }
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut val = [0; 4];
+        val.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(val))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, SnapshotError> {
+        Ok(self.u32()? as i32)
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut val = [0; 8];
+        val.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(val))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::Corrupt("length does not fit in memory"))
+    }
+
+    // Lengths are checked against the remaining input before anything is allocated.
+    pub fn len(&mut self, min_size: usize) -> Result<usize, SnapshotError> {
+        let len = self.usize()?;
+        if len.saturating_mul(min_size) > self.bytes.len() - self.index {
+            return Err(SnapshotError::Truncated);
+        }
+        Ok(len)
+    }
+
+    pub fn blob(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.len(1)?;
+        self.take(len)
+    }
+
+    pub fn value(&mut self) -> Result<Value, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(Value::Int(self.i32()?)),
+            1 => Ok(Value::Ref(self.u32()?)),
+            _ => Err(SnapshotError::Corrupt("invalid value tag")),
+        }
+    }
+
+    pub fn values(&mut self) -> Result<Vec<Value>, SnapshotError> {
+        let len = self.len(5)?;
+        (0..len).map(|_| self.value()).collect()
+    }
+}
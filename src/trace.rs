@@ -0,0 +1,238 @@
+/*
+ Copyright (c) 2022 Tor Ludwig Bogsveen
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy of
+ this software and associated documentation files (the "Software"), to deal in
+ the Software without restriction, including without limitation the rights to
+ use, copy, modify, merge, publish, distribute, sublicense, and/or sell copies of
+ the Software, and to permit persons to whom the Software is furnished to do so,
+ subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in all
+ copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS
+ FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR
+ COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER
+ IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN
+ CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+ */
+
+use std::fmt::Write;
+use std::time::Instant;
+
+use crate::hook::{ExecutionHook, FrameEvent, FrameTracker};
+use crate::error::Trap;
+use crate::json::escape;
+use crate::interpreter::{operand_values, CompilerCall, Instruction, Interpreter};
+
+// Timestamps in nanoseconds. `advance` is called once per executed instruction so
+// a clock can count time in instructions instead of reading the wall clock.
+pub trait Clock: Send + 'static {
+    fn now(&mut self) -> u64;
+
+    fn advance(&mut self, _instructions: u64) {}
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&mut self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+// Every instruction takes `step` nanoseconds, which makes traces reproducible.
+pub struct VirtualClock {
+    now: u64,
+    step: u64,
+}
+
+impl VirtualClock {
+    pub fn new(step: u64) -> VirtualClock {
+        VirtualClock { now: 0, step }
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&mut self) -> u64 {
+        self.now
+    }
+
+    fn advance(&mut self, instructions: u64) {
+        self.now += instructions * self.step;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TracePhase {
+    Begin,
+    End,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent {
+    pub name: String,
+    pub category: &'static str,
+    pub phase: TracePhase,
+    pub timestamp: u64,
+}
+
+// Records a begin/end pair for every call frame and host call, starting with a
+// frame for the code the run starts in. Frames are followed across coroutines as
+// `FrameTracker` describes, frames still open are closed when the trace is
+// exported.
+pub struct TraceRecorder<C: Clock = SystemClock> {
+    clock: C,
+    events: Vec<TraceEvent>,
+    frames: Vec<String>,
+    tracker: FrameTracker,
+    // A host call that has begun, ended by `on_host_call` or by the trap it raised.
+    host_call: Option<String>,
+    end: u64,
+    thread: u32,
+}
+
+impl Default for TraceRecorder<SystemClock> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceRecorder<SystemClock> {
+    pub fn new() -> TraceRecorder<SystemClock> {
+        TraceRecorder::with_clock(SystemClock::default())
+    }
+}
+
+impl<C: Clock> TraceRecorder<C> {
+    pub fn with_clock(clock: C) -> TraceRecorder<C> {
+        TraceRecorder { clock, events: Vec::new(), frames: Vec::new(), tracker: FrameTracker::new(), host_call: None, end: 0, thread: 1 }
+    }
+
+    // Interpreters traced into one file should each use their own thread id.
+    pub fn set_thread(&mut self, thread: u32) {
+        self.thread = thread;
+    }
+
+    pub fn events(&self) -> &[TraceEvent] {
+        &self.events
+    }
+
+    // Layout: the JSON object format of the Chrome trace-event spec, which
+    // chrome://tracing, Perfetto and speedscope all load.
+    pub fn to_json(&self) -> String {
+        let end = self.end;
+        let open = self.frames.iter().rev().map(|name| TraceEvent {
+            name: name.clone(),
+            category: "call",
+            phase: TracePhase::End,
+            timestamp: end,
+        });
+
+        let mut json = String::from("{\"traceEvents\":[");
+        for (i, event) in self.events.iter().cloned().chain(open).enumerate() {
+            if i != 0 {
+                json.push(',');
+            }
+            let _ = write!(
+                json,
+                "\n{{\"name\":{},\"cat\":\"{}\",\"ph\":\"{}\",\"ts\":{}.{:03},\"pid\":1,\"tid\":{}}}",
+                escape(&event.name),
+                event.category,
+                if event.phase == TracePhase::Begin { "B" } else { "E" },
+                event.timestamp / 1000,
+                event.timestamp % 1000,
+                self.thread,
+            );
+        }
+        json.push_str("\n],\"displayTimeUnit\":\"ns\"}\n");
+        json
+    }
+
+    fn record(&mut self, name: String, category: &'static str, phase: TracePhase) {
+        let timestamp = self.clock.now();
+        self.events.push(TraceEvent { name, category, phase, timestamp });
+    }
+
+    fn apply(&mut self, vm: &Interpreter, events: Vec<FrameEvent>) {
+        for event in events {
+            match event {
+                FrameEvent::Enter(entry) => {
+                    let name = frame_name(vm, entry);
+                    self.record(name.clone(), "call", TracePhase::Begin);
+                    self.frames.push(name);
+                }
+                FrameEvent::Exit => {
+                    if let Some(name) = self.frames.pop() {
+                        self.record(name, "call", TracePhase::End);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<C: Clock> ExecutionHook for TraceRecorder<C> {
+    fn before_instruction(&mut self, vm: &Interpreter, pc: usize, ins: Instruction) {
+        let events = self.tracker.before_instruction(vm, pc, ins);
+        self.apply(vm, events);
+        if let Instruction::CompilerCall = ins {
+            let call = operand_values(vm.program().code(), pc).first().map_or(0, |call| *call as u8);
+            let name = format!("{:?}", CompilerCall::from(call));
+            self.record(name.clone(), "host", TracePhase::Begin);
+            self.host_call = Some(name);
+        }
+    }
+
+    fn after_instruction(&mut self, vm: &Interpreter, _pc: usize, ins: Instruction) {
+        self.clock.advance(1);
+        self.end = self.clock.now();
+        let events = self.tracker.after_instruction(vm, ins);
+        self.apply(vm, events);
+    }
+
+    fn on_call(&mut self, vm: &Interpreter, _pc: usize, to: usize) {
+        let events = self.tracker.on_call(vm, to);
+        self.apply(vm, events);
+    }
+
+    fn on_return(&mut self, vm: &Interpreter, _pc: usize, _to: usize) {
+        let events = self.tracker.on_return(vm);
+        self.apply(vm, events);
+    }
+
+    fn on_host_call(&mut self, _vm: &Interpreter, _pc: usize, _call: CompilerCall) {
+        if let Some(name) = self.host_call.take() {
+            self.record(name, "host", TracePhase::End);
+        }
+    }
+
+    fn on_trap(&mut self, _vm: &Interpreter, _trap: &Trap) {
+        self.clock.advance(1);
+        self.end = self.clock.now();
+        if let Some(name) = self.host_call.take() {
+            self.record(name, "host", TracePhase::End);
+        }
+    }
+}
+
+// Prefers the function table, then the source location from the debug info.
+fn frame_name(vm: &Interpreter, pc: usize) -> String {
+    if let Some(name) = vm.function_name(pc) {
+        return name;
+    }
+    match vm.program().debug_info().location(pc) {
+        Some(location) => location.to_string(),
+        None => format!("fn@{}", pc),
+    }
+}